@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+drop index images_camera_id_captured_at;
+
+alter table images
+    drop column captured_at,
+    drop column camera_id;
+
+drop table cameras;
\ No newline at end of file
@@ -0,0 +1,17 @@
+-- Image sources
+-- zones are named polygons in image coordinates, e.g. {"bowl": [[x, y], ...]}
+create table cameras (
+    id serial primary key,
+    name text not null unique,
+    location text,
+    width integer,
+    height integer,
+    zones jsonb,
+    timezone text not null default 'UTC'
+);
+
+alter table images
+    add column camera_id integer references cameras(id),
+    add column captured_at timestamptz not null default now();
+
+create index images_camera_id_captured_at on images (camera_id, captured_at);
\ No newline at end of file
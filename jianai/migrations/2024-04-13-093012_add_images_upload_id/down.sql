@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table images drop column upload_id;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- Which upload session produced the image, to tell completed sessions from expired ones
+alter table images add column upload_id text unique;
+update images set upload_id = filename;
+alter table images alter column upload_id set not null;
\ No newline at end of file
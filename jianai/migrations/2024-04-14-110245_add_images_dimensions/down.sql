@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+alter table images
+    drop column format,
+    drop column height,
+    drop column width;
\ No newline at end of file
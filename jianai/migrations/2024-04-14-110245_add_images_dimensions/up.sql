@@ -0,0 +1,5 @@
+-- Filled in when the upload is decoded. Null for images from before that.
+alter table images
+    add column width integer,
+    add column height integer,
+    add column format text;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Detector score of the box. Null for segments from before it was kept.
+alter table segments add column confidence real;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop index images_filename;
+alter table images add constraint images_filename_key unique (filename);
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Files are named after their content, so identical uploads share one
+alter table images drop constraint images_filename_key;
+create index images_filename on images (filename);
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- When the retention rules removed the original. What is known about the image stays.
+alter table images add column expired_at timestamptz;
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Who removed which image and why. Kept after the image is gone.
+create table deletions (
+    id serial primary key,
+    image_id integer not null,
+    filename text not null,
+    digest bytea not null,
+    camera_id integer,
+    captured_at timestamptz not null,
+    deleted_by text not null,
+    reason text,
+    deleted_at timestamptz not null default now()
+);
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+alter table images drop column sessioned;
+drop table feeding_sessions;
\ No newline at end of file
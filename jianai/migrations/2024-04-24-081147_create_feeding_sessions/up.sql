@@ -0,0 +1,16 @@
+-- Frames of one cat at one camera, each at most the session gap after the previous one
+create table feeding_sessions (
+    id serial primary key,
+    camera_id integer not null references cameras(id),
+    tag_id integer not null references tags(id),
+    started_at timestamptz not null,
+    ended_at timestamptz not null,
+    duration_seconds integer not null generated always as (extract(epoch from ended_at - started_at)::integer) stored,
+    frame_count integer not null
+);
+
+create index feeding_sessions_camera_id_started_at on feeding_sessions (camera_id, started_at);
+create index feeding_sessions_tag_id_started_at on feeding_sessions (tag_id, started_at);
+
+-- Whether the sessions around the image are up to date with its segments
+alter table images add column sessioned boolean not null default false;
\ No newline at end of file
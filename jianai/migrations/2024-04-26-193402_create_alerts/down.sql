@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop table alerts;
+drop table alert_rules;
\ No newline at end of file
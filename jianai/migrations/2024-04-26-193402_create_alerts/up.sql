@@ -0,0 +1,18 @@
+-- Conditions over feeding data, checked on a schedule
+create table alert_rules (
+    id serial primary key,
+    name text not null unique,
+    condition jsonb not null,
+    enabled boolean not null default true
+);
+
+-- An alert fires once, and stays active until its condition no longer holds
+create table alerts (
+    id serial primary key,
+    rule_id integer not null references alert_rules(id) on delete cascade,
+    message text not null,
+    fired_at timestamptz not null default now(),
+    resolved_at timestamptz
+);
+
+create unique index alerts_active_rule_id on alerts (rule_id) where resolved_at is null;
\ No newline at end of file
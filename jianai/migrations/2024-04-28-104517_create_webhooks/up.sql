@@ -0,0 +1,25 @@
+-- Where to tell home automation about what happened, signed with `secret`
+create table webhooks (
+    id serial primary key,
+    url text not null,
+    secret text not null,
+    events text[] not null,
+    enabled boolean not null default true
+);
+
+-- Each event to each webhook, kept as the delivery log
+create table webhook_deliveries (
+    id serial primary key,
+    webhook_id integer not null references webhooks(id) on delete cascade,
+    event text not null,
+    payload jsonb not null,
+    created_at timestamptz not null default now(),
+    attempts integer not null default 0,
+    next_attempt_at timestamptz not null default now(),
+    delivered_at timestamptz,
+    failed_at timestamptz,
+    status_code integer,
+    last_error text
+);
+
+create index webhook_deliveries_pending on webhook_deliveries (next_attempt_at) where delivered_at is null and failed_at is null;
\ No newline at end of file
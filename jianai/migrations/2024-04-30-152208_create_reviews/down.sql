@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+drop table reviews;
+alter table segments drop column identification_scores;
\ No newline at end of file
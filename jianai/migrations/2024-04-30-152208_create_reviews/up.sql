@@ -0,0 +1,19 @@
+-- Scores of every tag by the identifier, e.g. {"3": 0.71, "5": 0.22}
+alter table segments add column identification_scores jsonb;
+
+-- Segments leased to reviewers, and their answers
+create table reviews (
+    id serial primary key,
+    segment_id integer not null references segments(id) on delete cascade,
+    reviewer text not null,
+    priority real not null,
+    leased_at timestamptz not null default now(),
+    expires_at timestamptz not null,
+    answered_at timestamptz,
+    tagged_as integer references tags(id),
+    low_quality boolean
+);
+
+-- A segment is leased to one reviewer at a time
+create unique index reviews_open_segment_id on reviews (segment_id) where answered_at is null;
+create index reviews_answered_at on reviews (answered_at);
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+alter table segments drop column identified_by;
\ No newline at end of file
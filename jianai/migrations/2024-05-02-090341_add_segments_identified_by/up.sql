@@ -0,0 +1,2 @@
+-- Version of the model that set `identified_as`
+alter table segments add column identified_by text;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Capture time may be long before, e.g. from EXIF. Rows from before this count as uploaded now.
+alter table images add column uploaded_at timestamptz not null default now();
@@ -0,0 +1,204 @@
+// Checks alert rules on a schedule. An alert fires once when its condition starts to hold,
+// and is resolved when it no longer does or the rule is disabled.
+use crate::model::{Alert, AlertRule, NewAlert};
+use crate::schema::*;
+use crate::types::Condition;
+use crate::webhooking::{self, ALERT_FIRED};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::{dsl, ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
+use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl};
+use serde_json::json;
+use tracing::{error, info};
+
+// Detections less sure than this may not be cats at all, so are not taken for unknown ones.
+const UNKNOWN_CONFIDENCE: f32 = 0.5;
+
+// `tagged_as`, `identified_as`, `low_quality` and `confidence` of a segment.
+type Sighting = (Option<i32>, Option<i32>, bool, Option<f32>);
+
+// A cat nobody knows: neither tagged by a human nor identified, yet surely a cat.
+fn is_unknown(sighting: &Sighting) -> bool {
+    let (tagged_as, identified_as, low_quality, confidence) = *sighting;
+    tagged_as.is_none()
+        && identified_as.is_none()
+        && !low_quality
+        && confidence.is_some_and(|x| x >= UNKNOWN_CONFIDENCE)
+}
+
+fn unknown_cats(sightings: &[Sighting], hours: u32) -> Option<String> {
+    let count = sightings.iter().filter(|x| is_unknown(x)).count();
+    (count > 0).then(|| format!("{count} unknown cats in the last {hours} hours"))
+}
+
+pub async fn alerting_loop(pg_pool: Pool<AsyncPgConnection>, alert_interval: u64) {
+    loop {
+        if let Err(e) = check_rules(&pg_pool).await {
+            error!("Checking alert rules failed: {e:?}");
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(alert_interval)).await;
+    }
+}
+
+async fn check_rules(pg_pool: &Pool<AsyncPgConnection>) -> Result<()> {
+    let mut pg_conn = pg_pool.get().await?;
+    let rules: Vec<AlertRule> = alert_rules::table
+        .select(AlertRule::as_select())
+        .order(alert_rules::id)
+        .load(&mut pg_conn)
+        .await?;
+    let now = Utc::now();
+    for rule in rules {
+        let firing = if rule.enabled {
+            // One broken rule should not keep the others from being checked.
+            match check_rule(&mut pg_conn, &rule, now).await {
+                Ok(firing) => firing,
+                Err(e) => {
+                    error!("Checking alert rule {} failed: {e:?}", rule.name);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        let active: Option<Alert> = alerts::table
+            .filter(alerts::rule_id.eq(rule.id))
+            .filter(alerts::resolved_at.is_null())
+            .select(Alert::as_select())
+            .first(&mut pg_conn)
+            .await
+            .optional()?;
+        match (firing, active) {
+            (Some(message), None) => {
+                info!("Alert {}: {message}", rule.name);
+                // Another instance may have fired it meanwhile.
+                let alert: Option<Alert> = diesel::insert_into(alerts::table)
+                    .values(NewAlert {
+                        rule_id: rule.id,
+                        message,
+                    })
+                    .on_conflict_do_nothing()
+                    .returning(Alert::as_returning())
+                    .get_result(&mut pg_conn)
+                    .await
+                    .optional()?;
+                if let Some(alert) = alert {
+                    let payload = json!({ "rule": rule.name, "alert": alert });
+                    webhooking::enqueue(&mut pg_conn, ALERT_FIRED, payload).await?;
+                }
+            }
+            (None, Some(alert)) => {
+                info!("Alert {} resolved: {}", rule.name, alert.message);
+                diesel::update(alerts::table.find(alert.id))
+                    .set(alerts::resolved_at.eq(now))
+                    .execute(&mut pg_conn)
+                    .await?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+async fn tag_name(pg_conn: &mut AsyncPgConnection, tag_id: i32) -> Result<String> {
+    let tag: Option<String> = tags::table
+        .find(tag_id)
+        .select(tags::tag)
+        .first(pg_conn)
+        .await
+        .optional()?;
+    Ok(tag.unwrap_or_else(|| format!("Cat {tag_id}")))
+}
+
+// Why the rule fires, or nothing if it does not.
+async fn check_rule(
+    pg_conn: &mut AsyncPgConnection,
+    rule: &AlertRule,
+    now: DateTime<Utc>,
+) -> Result<Option<String>> {
+    let condition: Condition = serde_json::from_value(rule.condition.clone())?;
+    match condition {
+        Condition::NotSeen { tag_id, hours } => {
+            let last: Option<DateTime<Utc>> = feeding_sessions::table
+                .filter(feeding_sessions::tag_id.eq(tag_id))
+                .select(dsl::max(feeding_sessions::ended_at))
+                .first(pg_conn)
+                .await?;
+            let tag = tag_name(pg_conn, tag_id).await?;
+            Ok(match last {
+                Some(last) if last >= now - Duration::hours(hours.into()) => None,
+                Some(last) => Some(format!("{tag} not seen at the bowl since {last}")),
+                None => Some(format!("{tag} never seen at the bowl")),
+            })
+        }
+        Condition::TooManyVisits {
+            tag_id,
+            visits,
+            timezone,
+        } => {
+            let timezone = timezone.unwrap_or("UTC".to_string());
+            let tz: Tz = timezone
+                .parse()
+                .map_err(|e| anyhow!("Bad timezone {timezone}: {e}"))?;
+            let today = now.with_timezone(&tz).date_naive().and_time(NaiveTime::MIN);
+            let midnight = tz
+                .from_local_datetime(&today)
+                .earliest()
+                .map_or(now - Duration::days(1), |x| x.with_timezone(&Utc));
+            let count: i64 = feeding_sessions::table
+                .filter(feeding_sessions::tag_id.eq(tag_id))
+                .filter(feeding_sessions::started_at.ge(midnight))
+                .count()
+                .get_result(pg_conn)
+                .await?;
+            let tag = tag_name(pg_conn, tag_id).await?;
+            Ok((count > i64::from(visits))
+                .then(|| format!("{tag} visited the bowl {count} times today")))
+        }
+        Condition::UnknownCat { camera_id, hours } => {
+            let mut query = segments::table
+                .inner_join(images::table)
+                .filter(segments::tagged_as.is_null())
+                .filter(segments::identified_as.is_null())
+                .filter(images::captured_at.ge(now - Duration::hours(hours.into())))
+                .select((
+                    segments::tagged_as,
+                    segments::identified_as,
+                    segments::low_quality,
+                    segments::confidence,
+                ))
+                .into_boxed();
+            if let Some(camera_id) = camera_id {
+                query = query.filter(images::camera_id.eq(camera_id));
+            }
+            let sightings: Vec<Sighting> = query.load(pg_conn).await?;
+            Ok(unknown_cats(&sightings, hours))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_on_a_cat_nobody_knows() {
+        assert_eq!(
+            unknown_cats(&[(None, None, false, Some(0.8))], 6).as_deref(),
+            Some("1 unknown cats in the last 6 hours")
+        );
+    }
+
+    #[test]
+    fn known_or_doubtful_cats_are_not_unknown() {
+        let sightings = [
+            (Some(3), None, false, Some(0.8)),
+            (None, Some(3), false, Some(0.8)),
+            (None, None, true, Some(0.8)),
+            (None, None, false, Some(0.3)),
+            (None, None, false, None),
+        ];
+        assert_eq!(unknown_cats(&sightings, 6), None);
+    }
+}
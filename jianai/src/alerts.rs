@@ -0,0 +1,116 @@
+// Managing alert rules, and what they fired.
+use crate::app_state::StoreState;
+use crate::handlers::*;
+use crate::model::{Alert, AlertRule, NewAlertRule};
+use crate::schema::*;
+use crate::types::Condition;
+use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel_async::RunQueryDsl;
+use rocket::{http::Status, serde::json::Json, *};
+use tracing::instrument;
+
+fn check_condition(rule: &NewAlertRule) -> anyhow::Result<()> {
+    let condition: Condition = serde_json::from_value(rule.condition.clone())?;
+    if let Condition::TooManyVisits {
+        timezone: Some(timezone),
+        ..
+    } = condition
+    {
+        timezone
+            .parse::<chrono_tz::Tz>()
+            .map_err(|e| BadRequest(format!("Bad timezone {timezone}: {e}")))?;
+    }
+    Ok(())
+}
+
+#[instrument]
+#[get("/rules")]
+pub async fn list_rules(
+    state: &State<StoreState>,
+) -> Result<Json<Vec<AlertRule>>, (Status, String)> {
+    let result: anyhow::Result<Vec<AlertRule>> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        alert_rules::table
+            .select(AlertRule::as_select())
+            .order(alert_rules::id)
+            .load(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[instrument]
+#[post("/rules", format = "application/json", data = "<rule>")]
+pub async fn create_rule(
+    state: &State<StoreState>,
+    rule: Json<NewAlertRule>,
+) -> Result<Json<AlertRule>, (Status, String)> {
+    let result: anyhow::Result<AlertRule> = try {
+        check_condition(&rule)?;
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::insert_into(alert_rules::table)
+            .values(rule.into_inner())
+            .returning(AlertRule::as_returning())
+            .get_result(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+// Active alerts stay until the next check finds the new condition does not hold.
+#[instrument]
+#[put("/rules/<id>", format = "application/json", data = "<rule>")]
+pub async fn update_rule(
+    state: &State<StoreState>,
+    id: i32,
+    rule: Json<NewAlertRule>,
+) -> Result<Json<AlertRule>, (Status, String)> {
+    let result: anyhow::Result<AlertRule> = try {
+        check_condition(&rule)?;
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::update(alert_rules::table.find(id))
+            .set(rule.into_inner())
+            .returning(AlertRule::as_returning())
+            .get_result(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+// Its alerts go with it.
+#[instrument]
+#[delete("/rules/<id>")]
+pub async fn delete_rule(state: &State<StoreState>, id: i32) -> (Status, String) {
+    let result: anyhow::Result<usize> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::delete(alert_rules::table.find(id))
+            .execute(&mut pg_conn)
+            .await?
+    };
+    match result {
+        Ok(0) => (Status::NotFound, String::new()),
+        Ok(_) => (Status::Ok, String::new()),
+        Err(e) => error_response(e),
+    }
+}
+
+// Active ones, or resolved ones too with `all`. Latest first.
+#[instrument]
+#[get("/?<all>")]
+pub async fn list_alerts(
+    state: &State<StoreState>,
+    all: Option<bool>,
+) -> Result<Json<Vec<Alert>>, (Status, String)> {
+    let result: anyhow::Result<Vec<Alert>> = try {
+        let mut query = alerts::table
+            .select(Alert::as_select())
+            .order(alerts::fired_at.desc())
+            .into_boxed();
+        if !all.unwrap_or(false) {
+            query = query.filter(alerts::resolved_at.is_null());
+        }
+        let mut pg_conn = state.pg_pool.get().await?;
+        query.load(&mut pg_conn).await?
+    };
+    result.map(Json).map_err(error_response)
+}
@@ -0,0 +1,286 @@
+// What the cats have been up to, for dashboards and the vet.
+use crate::app_state::StoreState;
+use crate::handlers::*;
+use crate::model::{FeedingSession, Tag};
+use crate::schema::*;
+use crate::types::{CatStats, PeriodStats};
+use chrono::{DateTime, Datelike, Days, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::{result::Error as DieselError, ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel_async::RunQueryDsl;
+use rocket::{http::Status, serde::json::Json, *};
+use std::collections::BTreeMap;
+use tracing::instrument;
+
+// Times are RFC 3339, `from` inclusive and `to` exclusive, on when the session started.
+#[instrument]
+#[get("/?<camera_id>&<tag_id>&<from>&<to>")]
+pub async fn list_sessions(
+    state: &State<StoreState>,
+    camera_id: Option<i32>,
+    tag_id: Option<i32>,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<Json<Vec<FeedingSession>>, (Status, String)> {
+    let result: anyhow::Result<Vec<FeedingSession>> = try {
+        let mut query = feeding_sessions::table
+            .select(FeedingSession::as_select())
+            .order(feeding_sessions::started_at)
+            .into_boxed();
+        if let Some(camera_id) = camera_id {
+            query = query.filter(feeding_sessions::camera_id.eq(camera_id));
+        }
+        if let Some(tag_id) = tag_id {
+            query = query.filter(feeding_sessions::tag_id.eq(tag_id));
+        }
+        if let Some(from) = from {
+            query = query.filter(feeding_sessions::started_at.ge(parse_time(&from)?));
+        }
+        if let Some(to) = to {
+            query = query.filter(feeding_sessions::started_at.lt(parse_time(&to)?));
+        }
+        let mut pg_conn = state.pg_pool.get().await?;
+        query.load(&mut pg_conn).await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[derive(Debug, Clone, Copy, FromFormField, clap::ValueEnum)]
+pub enum Period {
+    Day,
+    // Starting on Monday.
+    Week,
+}
+impl Period {
+    pub fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => date,
+            Period::Week => date - Days::new(date.weekday().num_days_from_monday().into()),
+        }
+    }
+
+    fn back(self, start: NaiveDate, count: u32) -> NaiveDate {
+        match self {
+            Period::Day => start - Days::new(count.into()),
+            Period::Week => start - Days::new(7 * u64::from(count)),
+        }
+    }
+
+    fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => start + Days::new(1),
+            Period::Week => start + Days::new(7),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StatsQuery {
+    period: Period,
+    // Starts of the first and last period.
+    from: NaiveDate,
+    to: NaiveDate,
+    trailing: u32,
+    timezone: Tz,
+}
+impl StatsQuery {
+    // Dates are `YYYY-MM-DD` in `timezone`, both inclusive. Defaults to the last 30 days or 12 weeks,
+    // compared to the 7 days or 4 weeks before each.
+    fn new(
+        period: Option<Period>,
+        from: Option<String>,
+        to: Option<String>,
+        trailing: Option<u32>,
+        timezone: Option<String>,
+    ) -> anyhow::Result<StatsQuery> {
+        let period = period.unwrap_or(Period::Day);
+        let timezone = timezone.unwrap_or("UTC".to_string());
+        let timezone: Tz = timezone
+            .parse()
+            .map_err(|_| BadRequest(format!("Unknown timezone {timezone}")))?;
+        let parse = |x: &str| NaiveDate::parse_from_str(x, "%Y-%m-%d");
+        let to = match to {
+            Some(to) => parse(&to)?,
+            None => Utc::now().with_timezone(&timezone).date_naive(),
+        };
+        let to = period.start(to);
+        let from = match from {
+            Some(from) => period.start(parse(&from)?),
+            None => period.back(
+                to,
+                match period {
+                    Period::Day => 29,
+                    Period::Week => 11,
+                },
+            ),
+        };
+        if from > to {
+            Err(BadRequest(format!("{from} is after {to}")))?;
+        }
+        Ok(StatsQuery {
+            period,
+            from,
+            to,
+            trailing: trailing.unwrap_or(match period {
+                Period::Day => 7,
+                Period::Week => 4,
+            }),
+            timezone,
+        })
+    }
+
+    // Midnight, or the first time of the day if midnight is skipped for daylight saving.
+    fn midnight(&self, date: NaiveDate) -> DateTime<Utc> {
+        let local = self
+            .timezone
+            .from_local_datetime(&date.and_time(NaiveTime::MIN))
+            .earliest()
+            .unwrap_or_else(|| {
+                self.timezone
+                    .from_utc_datetime(&date.and_time(NaiveTime::MIN))
+            });
+        local.with_timezone(&Utc)
+    }
+
+    fn periods(&self, sessions: &[&FeedingSession]) -> Vec<PeriodStats> {
+        let mut buckets: BTreeMap<NaiveDate, Vec<&FeedingSession>> = BTreeMap::new();
+        for session in sessions {
+            let date = session
+                .started_at
+                .with_timezone(&self.timezone)
+                .date_naive();
+            buckets
+                .entry(self.period.start(date))
+                .or_default()
+                .push(session);
+        }
+        let mut starts = Vec::new();
+        let mut start = self.period.back(self.from, self.trailing);
+        while start <= self.to {
+            starts.push(start);
+            start = self.period.next(start);
+        }
+        let totals: Vec<(i64, i64)> = starts
+            .iter()
+            .map(|start| {
+                let sessions = buckets.get(start).map_or(&[][..], Vec::as_slice);
+                (
+                    sessions.len() as i64,
+                    sessions.iter().map(|x| i64::from(x.duration_seconds)).sum(),
+                )
+            })
+            .collect();
+        let trailing = self.trailing as usize;
+        let ratio = |value: i64, average: f64| (average > 0.0).then(|| value as f64 / average);
+        starts
+            .iter()
+            .enumerate()
+            .skip(trailing)
+            .map(|(i, start)| {
+                let history = &totals[i - trailing..i];
+                let average = |f: fn(&(i64, i64)) -> i64| {
+                    if trailing == 0 {
+                        0.0
+                    } else {
+                        history.iter().map(f).sum::<i64>() as f64 / trailing as f64
+                    }
+                };
+                let trailing_visits = average(|x| x.0);
+                let trailing_seconds = average(|x| x.1);
+                let sessions = buckets.get(start).map_or(&[][..], Vec::as_slice);
+                let (visits, total_seconds) = totals[i];
+                PeriodStats {
+                    start: *start,
+                    visits,
+                    total_seconds,
+                    first_visit: sessions.iter().map(|x| x.started_at).min(),
+                    last_visit: sessions.iter().map(|x| x.ended_at).max(),
+                    trailing_visits,
+                    trailing_seconds,
+                    visits_vs_trailing: ratio(visits, trailing_visits),
+                    seconds_vs_trailing: ratio(total_seconds, trailing_seconds),
+                }
+            })
+            .collect()
+    }
+}
+
+async fn cat_stats(
+    state: &StoreState,
+    query: &StatsQuery,
+    tag_id: Option<i32>,
+) -> anyhow::Result<Vec<CatStats>> {
+    let mut pg_conn = state.pg_pool.get().await?;
+    let mut tags_query = tags::table
+        .select(Tag::as_select())
+        .order(tags::id)
+        .into_boxed();
+    let mut sessions_query = feeding_sessions::table
+        .select(FeedingSession::as_select())
+        .filter(
+            feeding_sessions::started_at
+                .ge(query.midnight(query.period.back(query.from, query.trailing))),
+        )
+        .filter(feeding_sessions::started_at.lt(query.midnight(query.period.next(query.to))))
+        .into_boxed();
+    if let Some(tag_id) = tag_id {
+        tags_query = tags_query.filter(tags::id.eq(tag_id));
+        sessions_query = sessions_query.filter(feeding_sessions::tag_id.eq(tag_id));
+    }
+    let tags: Vec<Tag> = tags_query.load(&mut pg_conn).await?;
+    if tag_id.is_some() && tags.is_empty() {
+        Err(DieselError::NotFound)?;
+    }
+    let sessions: Vec<FeedingSession> = sessions_query.load(&mut pg_conn).await?;
+    Ok(tags
+        .into_iter()
+        .map(|tag| {
+            let own: Vec<&FeedingSession> =
+                sessions.iter().filter(|x| x.tag_id == tag.id).collect();
+            CatStats {
+                tag_id: tag.id,
+                tag: tag.tag,
+                periods: query.periods(&own),
+            }
+        })
+        .collect())
+}
+
+// Every cat, including those not seen.
+#[instrument]
+#[get("/?<period>&<from>&<to>&<trailing>&<timezone>")]
+pub async fn all_cat_stats(
+    state: &State<StoreState>,
+    period: Option<Period>,
+    from: Option<String>,
+    to: Option<String>,
+    trailing: Option<u32>,
+    timezone: Option<String>,
+) -> Result<Json<Vec<CatStats>>, (Status, String)> {
+    let result: anyhow::Result<Vec<CatStats>> = try {
+        let query = StatsQuery::new(period, from, to, trailing, timezone)?;
+        cat_stats(state, &query, None).await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[instrument]
+#[get("/<tag_id>?<period>&<from>&<to>&<trailing>&<timezone>")]
+pub async fn get_cat_stats(
+    state: &State<StoreState>,
+    tag_id: i32,
+    period: Option<Period>,
+    from: Option<String>,
+    to: Option<String>,
+    trailing: Option<u32>,
+    timezone: Option<String>,
+) -> Result<Json<CatStats>, (Status, String)> {
+    let result: anyhow::Result<CatStats> = try {
+        let query = StatsQuery::new(period, from, to, trailing, timezone)?;
+        cat_stats(state, &query, Some(tag_id))
+            .await?
+            .pop()
+            .ok_or(DieselError::NotFound)?
+    };
+    result.map(Json).map_err(error_response)
+}
@@ -1,11 +1,14 @@
+use crate::ingest::ImageLimits;
+use crate::publishing::Publisher;
+use crate::storage::Storage;
+use chrono::{DateTime, Utc};
 use derivative::Derivative;
 use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection};
 use redis::{aio::MultiplexedConnection, Client};
 use redis_pool::RedisPool;
-use std::{
-    path::PathBuf,
-    sync::atomic::{AtomicU64, Ordering},
-};
+use std::{path::PathBuf, sync::Mutex};
+use tokio::sync::broadcast;
+use ulid::{Generator, Ulid};
 
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -13,17 +16,79 @@ pub struct StoreState {
     #[derivative(Debug = "ignore")]
     pub redis_pool: RedisPool<Client, MultiplexedConnection>,
     pub pg_pool: Pool<AsyncPgConnection>,
-    pub id_prefix: String,
-    pub id_counter: AtomicU64,
+    #[derivative(Debug = "ignore")]
+    pub id_generator: Mutex<Generator>,
     pub upload_image_http_path: String,
-    pub image_folder: PathBuf,
+    pub storage: Storage,
+    pub partial_folder: PathBuf,
+    pub upload_session_ttl: u64,
+    pub image_limits: ImageLimits,
+    pub thumbnail_sizes: Vec<u32>,
+    #[derivative(Debug = "ignore")]
+    pub label_font: Option<ab_glyph::FontArc>,
+    #[derivative(Debug = "ignore")]
+    pub meta_schema: jsonschema::Validator,
+    // Messages of the live event channel, see `streaming`.
+    pub events: broadcast::Sender<String>,
+    #[derivative(Debug = "ignore")]
+    pub publisher: Option<Publisher>,
 }
 impl StoreState {
+    // A ULID: milliseconds since the epoch and 80 random bits, so ids sort by creation time and
+    // need no coordination between hosts or restarts. Monotonic within this process.
     pub fn get_id(&self) -> String {
-        format!(
-            "{}{}",
-            self.id_prefix,
-            self.id_counter.fetch_add(1, Ordering::SeqCst)
-        )
+        let mut generator = self.id_generator.lock().unwrap_or_else(|e| e.into_inner());
+        // Running out of random bits within one millisecond only costs the ordering.
+        generator
+            .generate()
+            .unwrap_or_else(|_| Ulid::new())
+            .to_string()
+    }
+}
+
+// When an upload id from `get_id` was made, and its random part.
+pub fn parse_id(upload_id: &str) -> anyhow::Result<(DateTime<Utc>, u128)> {
+    let ulid = Ulid::from_string(upload_id)?;
+    Ok((ulid.datetime().into(), ulid.random()))
+}
+
+// Redis sorted set of files put in storage for uploads, scored by when, until their rows are
+// in. What stays long is for the sweeper.
+pub const PERSISTING_KEY: &str = "persisting-images";
+
+// Redis key holding the `PendingUpload` of an upload session.
+pub fn upload_key(upload_id: &str) -> String {
+    format!("upload-{upload_id}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ulids() {
+        let (created_at, _) = parse_id("01ARZ3NDEKTSV4RRFFQ69G5FAV").unwrap();
+        assert_eq!(created_at.to_rfc3339(), "2016-07-30T23:54:10.259+00:00");
+        let id = Ulid::from_parts(1_714_564_800_000, 0xDEAD_BEEF_0123_4567_89AB).to_string();
+        let (created_at, random) = parse_id(&id).unwrap();
+        assert_eq!(created_at.to_rfc3339(), "2024-05-01T12:00:00+00:00");
+        assert_eq!(random, 0xDEAD_BEEF_0123_4567_89AB);
+    }
+
+    #[test]
+    fn refuses_what_is_no_ulid() {
+        assert!(parse_id("host12345").is_err());
+        assert!(parse_id("").is_err());
+        // Crockford's base 32 has no U.
+        assert!(parse_id("01ARZ3NDEKTSV4RRFFQ69G5FAU").is_err());
+    }
+
+    #[test]
+    fn ids_sort_by_creation() {
+        let mut generator = Generator::new();
+        let first = generator.generate().unwrap().to_string();
+        let second = generator.generate().unwrap().to_string();
+        assert!(first < second);
+        assert!(parse_id(&first).unwrap().0 <= parse_id(&second).unwrap().0);
     }
 }
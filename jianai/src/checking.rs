@@ -0,0 +1,157 @@
+// Files and rows are not written atomically, so storage and `images.filename` drift apart.
+// Reports files without rows, rows without files and files not matching their digest.
+use crate::cli::Repair;
+use crate::ingest::digest;
+use crate::model::{Image, NewDeletion};
+use crate::schema::*;
+use crate::sessioning::unsession;
+use crate::storage::Storage;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel_async::{
+    pooled_connection::bb8::Pool, scoped_futures::ScopedFutureExt, AsyncConnection,
+    AsyncPgConnection, RunQueryDsl,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+// Under storage, where suspicious files are moved for a human to look at.
+pub const QUARANTINE_FOLDER: &str = ".quarantine";
+
+pub async fn fsck(
+    pg_pool: Pool<AsyncPgConnection>,
+    storage: Storage,
+    repair: Option<Repair>,
+    min_age: u64,
+) -> Result<()> {
+    let mut pg_conn = pg_pool.get().await?;
+    // Expired images have no file on purpose.
+    let rows: Vec<(i32, String, Vec<u8>)> = images::table
+        .filter(images::expired_at.is_null())
+        .select((images::id, images::filename, images::digest))
+        .order(images::id)
+        .load(&mut pg_conn)
+        .await?;
+    // Folders starting with a dot hold what is not images.
+    let stored: HashMap<String, DateTime<Utc>> = storage
+        .list(None)
+        .await?
+        .into_iter()
+        .filter(|x| !x.name.starts_with('.'))
+        .map(|x| (x.name, x.modified))
+        .collect();
+
+    // Recent files may belong to uploads still on their way.
+    let recent = Utc::now() - Duration::from_secs(min_age);
+    let recorded: HashSet<&str> = rows.iter().map(|x| x.1.as_str()).collect();
+    let mut orphans: Vec<&str> = stored
+        .iter()
+        .filter(|(name, modified)| !recorded.contains(name.as_str()) && **modified < recent)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    orphans.sort();
+    for name in &orphans {
+        println!("orphan {name}");
+    }
+
+    let mut missing = Vec::new();
+    let mut corrupt = Vec::new();
+    let mut corrupt_files = Vec::new();
+    // Identical uploads share a file.
+    let mut digests: HashMap<&str, Vec<u8>> = HashMap::new();
+    for (id, filename, expected) in &rows {
+        if !stored.contains_key(filename) {
+            println!("missing {filename} of image {id}");
+            missing.push(*id);
+            continue;
+        }
+        if !digests.contains_key(filename.as_str()) {
+            let content = storage.get(filename).await?;
+            digests.insert(filename, digest(&content));
+        }
+        if digests[filename.as_str()] != *expected {
+            println!("corrupt {filename} of image {id}");
+            corrupt.push(*id);
+            if !corrupt_files.contains(&filename.as_str()) {
+                corrupt_files.push(filename.as_str());
+            }
+        }
+    }
+    println!(
+        "{} images, {} files: {} orphaned, {} missing, {} corrupt",
+        rows.len(),
+        stored.len(),
+        orphans.len(),
+        missing.len(),
+        corrupt.len()
+    );
+
+    match repair {
+        None => {}
+        // Rows are kept, so they show up as missing until dealt with.
+        Some(Repair::Quarantine) => {
+            for name in orphans.iter().chain(&corrupt_files) {
+                let content = storage.get(name).await?;
+                storage
+                    .put(&format!("{QUARANTINE_FOLDER}/{name}"), content)
+                    .await?;
+                storage.delete(name).await?;
+                println!("quarantined {name}");
+            }
+        }
+        Some(Repair::Delete) => {
+            for name in orphans.iter().chain(&corrupt_files) {
+                storage.delete(name).await?;
+                println!("deleted {name}");
+            }
+            // Recorded in `deletions` like deletions through the API, by `fsck`.
+            let missing: HashSet<i32> = missing.into_iter().collect();
+            let ids: Vec<i32> = missing.iter().copied().chain(corrupt).collect();
+            pg_conn
+                .transaction(|pg_conn| {
+                    (async move {
+                        diesel::delete(segments::table.filter(segments::image_id.eq_any(&ids)))
+                            .execute(pg_conn)
+                            .await?;
+                        let deleted: Vec<Image> =
+                            diesel::delete(images::table.filter(images::id.eq_any(&ids)))
+                                .returning(Image::as_returning())
+                                .get_results(pg_conn)
+                                .await?;
+                        unsession(pg_conn, &deleted).await?;
+                        let records: Vec<NewDeletion> = deleted
+                            .iter()
+                            .map(|x| NewDeletion {
+                                image_id: x.id,
+                                filename: x.filename.clone(),
+                                digest: x.digest.clone(),
+                                camera_id: x.camera_id,
+                                captured_at: x.captured_at,
+                                deleted_by: "fsck".to_string(),
+                                reason: Some(
+                                    if missing.contains(&x.id) {
+                                        "file missing"
+                                    } else {
+                                        "file does not match its digest"
+                                    }
+                                    .to_string(),
+                                ),
+                            })
+                            .collect();
+                        diesel::insert_into(deletions::table)
+                            .values(&records)
+                            .execute(pg_conn)
+                            .await?;
+                        println!("deleted {} images", deleted.len());
+                        Ok(()) as Result<(), diesel::result::Error>
+                    })
+                    .scope_boxed()
+                })
+                .await?;
+        }
+    }
+    Ok(())
+}
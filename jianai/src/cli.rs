@@ -1,3 +1,5 @@
+use crate::analytics::Period;
+use crate::reporting::{ReportFormat, Table};
 use clap::*;
 use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
 use std::path::PathBuf;
@@ -29,14 +31,48 @@ impl PGParams {
 }
 
 #[derive(Parser, Clone, Debug)]
-#[command(author, version, about, long_about = None)]
+pub struct MqttParams {
+    /// MQTT broker to publish what is found to, as `host:port`.
+    #[arg(long)]
+    pub mqtt_broker: Option<String>,
+    #[arg(long, env = "MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
+    #[arg(long, env = "MQTT_PASSWORD")]
+    pub mqtt_password: Option<String>,
+    /// Prefix of the topics published to.
+    #[arg(long, default_value = "jianai")]
+    pub mqtt_topic_prefix: String,
+    /// Prefix Home Assistant looks for discovery messages under.
+    #[arg(long, default_value = "homeassistant")]
+    pub mqtt_discovery_prefix: String,
+}
+
+// Commands needing no database, Redis or storage, tried before `Params` so their settings need
+// not be given. The help of `Params` tells of them.
+#[derive(Parser, Clone, Debug)]
+#[command(rename_all = "lower")]
+pub enum Standalone {
+    /// Tell when an upload id was made.
+    ParseId { upload_id: String },
+}
+
+#[derive(Parser, Clone, Debug)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "`jianai parseid <UPLOAD_ID>` tells when an upload id was made, with none of the above."
+)]
 pub struct Params {
     #[command(flatten)]
     pub pg_params: PGParams,
     #[arg(short, long, default_value = "redis://localhost:6379/")]
     pub redis_address: String,
-    #[arg(short, long)]
-    pub image_folder: PathBuf,
+    /// Folder or `s3://bucket/prefix` to keep images in.
+    /// S3 settings, e.g. `AWS_ENDPOINT` for MinIO, come from `AWS_*` variables.
+    #[arg(short = 'i', long, alias = "image-folder")]
+    pub storage: String,
     #[arg(short, long, default_value = "localhost:3000")]
     pub listen_address: String,
 
@@ -44,15 +80,149 @@ pub struct Params {
     pub cmd: SubCmd,
 }
 
+// Parsed once, so the size of `Store` does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Clone, Debug)]
 #[command(rename_all = "lower")]
 pub enum SubCmd {
     Store {
         #[arg(short, long, default_value = "/upload_image")]
         upload_image_http_path: String,
+        /// JSON Schema the upload metadata must conform to. Defaults to the bundled one.
+        #[arg(long)]
+        meta_schema: Option<PathBuf>,
+        /// Seconds an upload session waits for its image before it expires.
+        #[arg(long, default_value = "3600")]
+        upload_session_ttl: u64,
+        /// Seconds between sweeps for abandoned uploads.
+        #[arg(long, default_value = "600")]
+        sweep_interval: u64,
+        /// JSON file of retention rules. Everything is kept if not given.
+        #[arg(long)]
+        retention_rules: Option<PathBuf>,
+        /// Seconds between enforcing the retention rules.
+        #[arg(long, default_value = "86400")]
+        retention_interval: u64,
+        /// Seconds between frames of a cat for them to be in the same feeding session.
+        #[arg(long, default_value = "120")]
+        session_gap: u64,
+        /// Seconds between updates of feeding sessions with newly segmented images.
+        #[arg(long, default_value = "60")]
+        session_interval: u64,
+        /// Seconds between checks of alert rules.
+        #[arg(long, default_value = "60")]
+        alert_interval: u64,
+        /// Seconds between sends of queued webhook deliveries.
+        #[arg(long, default_value = "5")]
+        webhook_interval: u64,
+        /// Local folder where chunks of resumable uploads are assembled.
+        /// With several store processes, either route all requests of an upload to the same
+        /// one, e.g. by hashing the upload id in the path, or give them all the same folder on a
+        /// shared file system. Otherwise a chunk reaching another process looks like offset 0.
+        #[arg(long, default_value = "/var/tmp/jianai")]
+        partial_folder: PathBuf,
+        /// Largest image accepted, in bytes. Multipart uploads may carry up to 16 of them.
+        #[arg(long, default_value = "33554432")]
+        max_image_bytes: u64,
+        /// Limit of the longer side, in pixels.
+        #[arg(long, default_value = "8192")]
+        max_image_dimension: u32,
+        /// Extension of the format to re-encode uploads to, e.g. `jpg`. Kept as is if not given.
+        #[arg(long)]
+        canonical_format: Option<String>,
+        /// Longer side of thumbnails, in pixels.
+        #[arg(long, value_delimiter = ',', default_value = "256,1024")]
+        thumbnail_sizes: Vec<u32>,
+        /// TrueType font for labels on annotated images.
+        #[arg(
+            long,
+            default_value = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf"
+        )]
+        label_font: PathBuf,
+        // Where cats were last seen changes with their tags.
+        #[command(flatten)]
+        mqtt_params: MqttParams,
     },
     Segment {
         #[arg(short = 'p', long)]
         model_path: PathBuf,
+        /// Least confidence of a detection to be kept.
+        #[arg(long, default_value = "0.5")]
+        threshold: f32,
+        #[command(flatten)]
+        mqtt_params: MqttParams,
+    },
+    /// Move images named after their upload id to content-addressed names.
+    Migrate,
+    /// Enforce retention rules once.
+    Retain {
+        rules: PathBuf,
+        /// Only tell what would be removed.
+        #[arg(long)]
+        dry_run: bool,
     },
+    /// Check storage against the images table.
+    Fsck {
+        /// What to do with orphaned and corrupt files. Only reports if not given.
+        #[arg(long)]
+        repair: Option<Repair>,
+        /// Seconds a file without a row must have been there to count as orphaned.
+        #[arg(long, default_value = "3600")]
+        min_age: u64,
+    },
+    /// Run the detector over images with human-verified boxes and score it.
+    Evaluate {
+        #[arg(short = 'p', long)]
+        model_path: PathBuf,
+        /// JSON list of `{"image_id": 1, "boxes": [{"point1": {"x": 0, "y": 0}, "point2": …}]}`.
+        #[arg(long)]
+        ground_truth: PathBuf,
+        /// Least confidence of a detection to be kept, for precision and recall.
+        #[arg(long, default_value = "0.5")]
+        threshold: f32,
+        /// Where to write the JSON report. Standard output if not given.
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Report how the identifier did against human tags.
+    Report {
+        /// RFC 3339, inclusive, on when images were captured.
+        #[arg(long)]
+        from: Option<String>,
+        /// RFC 3339, exclusive.
+        #[arg(long)]
+        to: Option<String>,
+        #[arg(long)]
+        camera_id: Option<i32>,
+        /// Periods of accuracy over time, in UTC.
+        #[arg(long, value_enum, default_value = "day")]
+        period: Period,
+        #[arg(long, value_enum, default_value = "json")]
+        format: ReportFormat,
+        /// Which table to print as CSV.
+        #[arg(long, value_enum, default_value = "overall")]
+        table: Table,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Repair {
+    /// Move the files to `.quarantine`.
+    Quarantine,
+    /// Delete the files, and the rows of missing or corrupt ones.
+    Delete,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_id_needs_no_settings() {
+        let Standalone::ParseId { upload_id } =
+            Standalone::try_parse_from(["jianai", "parseid", "01ARZ3NDEKTSV4RRFFQ69G5FAV"])
+                .unwrap();
+        assert_eq!(upload_id, "01ARZ3NDEKTSV4RRFFQ69G5FAV");
+        assert!(Standalone::try_parse_from(["jianai", "store"]).is_err());
+    }
 }
@@ -0,0 +1,236 @@
+// Scores the detector against human-verified boxes, to compare detector versions.
+// Only cats count, as only they are kept as segments.
+use crate::model::Image;
+use crate::schema::*;
+use crate::segmenting::segmenting;
+use crate::storage::Storage;
+use crate::types::{self, DetectionReport, GroundTruth, ImageEvaluation};
+use anyhow::{anyhow, Result};
+use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl};
+use std::path::{Path, PathBuf};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+// Detections down to this count towards average precision.
+const LEAST_CONFIDENCE: f32 = 0.001;
+
+pub async fn load_ground_truth(path: &Path) -> Result<Vec<GroundTruth>> {
+    Ok(serde_json::from_str(
+        &tokio::fs::read_to_string(path).await?,
+    )?)
+}
+
+pub async fn evaluate(
+    pg_pool: Pool<AsyncPgConnection>,
+    storage: Storage,
+    model_path: PathBuf,
+    ground_truth: Vec<GroundTruth>,
+    threshold: f32,
+) -> Result<DetectionReport> {
+    let mut pg_conn = pg_pool.get().await?;
+    let ids: Vec<i32> = ground_truth.iter().map(|x| x.image_id).collect();
+    let images: Vec<Image> = images::table
+        .filter(images::id.eq_any(&ids))
+        .select(Image::as_select())
+        .load(&mut pg_conn)
+        .await?;
+    drop(pg_conn);
+
+    // Boxes and confidences of cats, per image in the order of `ground_truth`.
+    let mut detections: Vec<Vec<(types::Box, f32)>> = Vec::new();
+    for truth in &ground_truth {
+        let image = images
+            .iter()
+            .find(|x| x.id == truth.image_id)
+            .ok_or_else(|| anyhow!("No image {}", truth.image_id))?;
+        if image.expired_at.is_some() {
+            Err(anyhow!("The original of image {} has expired", image.id))?;
+        }
+        let content = storage.get(&image.filename).await?;
+        let model_path = model_path.clone();
+        let segments =
+            spawn_blocking(move || segmenting(content, model_path, LEAST_CONFIDENCE)).await??;
+        detections.push(
+            segments
+                .into_iter()
+                .filter(|x| x.class == "cat")
+                .map(|x| (x.bounding_box, x.posibility))
+                .collect(),
+        );
+        info!("Evaluated image {}", image.id);
+    }
+
+    let thresholds: Vec<f32> = (0..10).map(|i| 0.5 + 0.05 * i as f32).collect();
+    let aps: Vec<f64> = thresholds
+        .iter()
+        .map(|&iou| average_precision(&ground_truth, &detections, iou))
+        .collect();
+
+    let mut per_image = Vec::new();
+    let (mut true_positives, mut detected) = (0, 0);
+    for (truth, found) in ground_truth.iter().zip(&detections) {
+        let kept: Vec<_> = found.iter().filter(|x| x.1 >= threshold).copied().collect();
+        let matched = matches(&truth.boxes, &kept, 0.5);
+        true_positives += matched.iter().filter(|x| x.is_some()).count();
+        detected += kept.len();
+        per_image.push(ImageEvaluation {
+            image_id: truth.image_id,
+            ground_truth: truth.boxes.len(),
+            detected: kept.len(),
+            missed: truth
+                .boxes
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| !matched.contains(&Some(*i)))
+                .map(|(_, x)| *x)
+                .collect(),
+            false_positives: kept
+                .iter()
+                .zip(&matched)
+                .filter(|(_, x)| x.is_none())
+                .map(|(x, _)| x.0)
+                .collect(),
+        });
+    }
+    let total: usize = ground_truth.iter().map(|x| x.boxes.len()).sum();
+    let ratio = |part: usize, whole: usize| (whole > 0).then(|| part as f64 / whole as f64);
+    Ok(DetectionReport {
+        model: model_path.display().to_string(),
+        threshold,
+        images: ground_truth.len(),
+        ground_truth: total,
+        map50: aps[0],
+        map50_95: aps.iter().sum::<f64>() / aps.len() as f64,
+        precision: ratio(true_positives, detected),
+        recall: ratio(true_positives, total),
+        per_image,
+    })
+}
+
+// For each detection, most confident first, the ground truth box it is taken to be, if any.
+// Each goes to the box it overlaps most that is not taken yet.
+fn matches(truth: &[types::Box], found: &[(types::Box, f32)], iou: f32) -> Vec<Option<usize>> {
+    let mut order: Vec<usize> = (0..found.len()).collect();
+    order.sort_by(|&a, &b| found[b].1.total_cmp(&found[a].1));
+    let mut taken = vec![false; truth.len()];
+    let mut matched = vec![None; found.len()];
+    for i in order {
+        let best = truth
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| !taken[*j])
+            .map(|(j, x)| (j, x.iou(&found[i].0)))
+            .filter(|(_, x)| *x >= iou)
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((j, _)) = best {
+            taken[j] = true;
+            matched[i] = Some(j);
+        }
+    }
+    matched
+}
+
+// Area under the precision-recall curve over all detections, precision made non-increasing.
+fn average_precision(
+    ground_truth: &[GroundTruth],
+    detections: &[Vec<(types::Box, f32)>],
+    iou: f32,
+) -> f64 {
+    let total: usize = ground_truth.iter().map(|x| x.boxes.len()).sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let mut scored: Vec<(f32, bool)> = ground_truth
+        .iter()
+        .zip(detections)
+        .flat_map(|(truth, found)| {
+            matches(&truth.boxes, found, iou)
+                .into_iter()
+                .zip(found)
+                .map(|(matched, x)| (x.1, matched.is_some()))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    let mut curve = Vec::new();
+    let (mut true_positives, mut seen) = (0, 0);
+    for (_, correct) in scored {
+        seen += 1;
+        true_positives += usize::from(correct);
+        curve.push((
+            true_positives as f64 / total as f64,
+            true_positives as f64 / seen as f64,
+        ));
+    }
+    let mut ap = 0.0;
+    let mut best = 0.0;
+    let mut recall = curve.last().map_or(0.0, |x| x.0);
+    for &(r, p) in curve.iter().rev() {
+        ap += (recall - r) * best;
+        recall = r;
+        best = f64::max(best, p);
+    }
+    ap + recall * best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Point;
+
+    fn square(x: f32, y: f32, side: f32) -> types::Box {
+        types::Box {
+            point1: Point { x, y },
+            point2: Point {
+                x: x + side,
+                y: y + side,
+            },
+        }
+    }
+
+    #[test]
+    fn matches_most_confident_first() {
+        let truth = [square(0.0, 0.0, 10.0), square(5.0, 0.0, 10.0)];
+        let found = [
+            // IoU 1 with the first box.
+            (square(0.0, 0.0, 10.0), 0.6),
+            // IoU 0.43 with the first box and 0.82 with the second.
+            (square(4.0, 0.0, 10.0), 0.9),
+            // A duplicate, with both boxes taken.
+            (square(0.0, 0.0, 10.0), 0.5),
+            (square(50.0, 50.0, 10.0), 0.95),
+        ];
+        assert_eq!(
+            matches(&truth, &found, 0.5),
+            vec![Some(0), Some(1), None, None]
+        );
+    }
+
+    #[test]
+    fn average_precision_follows_the_curve() {
+        let ground_truth = vec![
+            GroundTruth {
+                image_id: 1,
+                boxes: vec![square(0.0, 0.0, 10.0), square(20.0, 0.0, 10.0)],
+            },
+            GroundTruth {
+                image_id: 2,
+                boxes: vec![square(0.0, 0.0, 10.0)],
+            },
+        ];
+        let detections = vec![
+            vec![
+                (square(0.0, 0.0, 10.0), 0.9),
+                (square(50.0, 50.0, 10.0), 0.8),
+                (square(20.0, 0.0, 10.0), 0.7),
+            ],
+            vec![],
+        ];
+        // Recall and precision go (1/3, 1), (1/3, 1/2), (2/3, 2/3). Made non-increasing,
+        // precision is 1 up to recall 1/3 and 2/3 up to 2/3: 1/3 + 2/9.
+        let ap = average_precision(&ground_truth, &detections, 0.5);
+        assert!((ap - 5.0 / 9.0).abs() < 1e-9);
+        assert_eq!(average_precision(&[], &[], 0.5), 0.0);
+    }
+}
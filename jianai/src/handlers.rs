@@ -1,16 +1,169 @@
-use crate::app_state::StoreState;
+use crate::app_state::{upload_key, StoreState, PERSISTING_KEY};
+use crate::ingest::{format_name, process_image, StoredImage};
+use crate::model::*;
 use crate::schema::*;
-use diesel::ExpressionMethods;
-use diesel_async::RunQueryDsl;
+use crate::streaming::{self, IMAGE_UPLOADED};
+use crate::types::{MetaError, PendingUpload, UploadStatus};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use diesel::{
+    result::{DatabaseErrorKind, Error as DieselError},
+    ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
 use redis::AsyncCommands;
 use rocket::{
+    form::Form,
     fs::TempFile,
     http::{Header, Status},
+    serde::json::Json,
     *,
 };
-use sha3::{Digest, Sha3_224};
+use serde_json::{json, Value};
+use std::fmt;
+use tokio::io::AsyncReadExt;
 use tracing::instrument;
 
+// Errors caused by what the client sent, as opposed to our own failures.
+#[derive(Debug)]
+pub struct BadRequest(pub String);
+impl fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for BadRequest {}
+
+#[derive(Debug)]
+pub struct UploadExpired(pub String);
+impl fmt::Display for UploadExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upload session {} expired", self.0)
+    }
+}
+impl std::error::Error for UploadExpired {}
+
+// Metadata that is JSON, but not what the schema asks for.
+#[derive(Debug)]
+pub struct InvalidMeta(pub Vec<MetaError>);
+impl fmt::Display for InvalidMeta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Metadata does not match the schema")
+    }
+}
+impl std::error::Error for InvalidMeta {}
+
+// A resumable upload chunk not starting where the previous one ended, which is where we are.
+#[derive(Debug)]
+pub struct OffsetMismatch(pub u64);
+impl fmt::Display for OffsetMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upload is at offset {}", self.0)
+    }
+}
+impl std::error::Error for OffsetMismatch {}
+
+#[derive(Debug)]
+pub struct UnsupportedImage(pub String);
+impl fmt::Display for UnsupportedImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Not an image we can read: {}", self.0)
+    }
+}
+impl std::error::Error for UnsupportedImage {}
+
+#[derive(Debug)]
+pub struct ImageTooLarge(pub String);
+impl fmt::Display for ImageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Image too large: {}", self.0)
+    }
+}
+impl std::error::Error for ImageTooLarge {}
+
+#[derive(Debug)]
+pub struct DigestMismatch;
+impl fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest of the received image does not match")
+    }
+}
+impl std::error::Error for DigestMismatch {}
+
+// The original was removed by the retention rules.
+#[derive(Debug)]
+pub struct ImageExpired(pub i32);
+impl fmt::Display for ImageExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Original of image {} is no longer kept", self.0)
+    }
+}
+impl std::error::Error for ImageExpired {}
+
+// Another request is writing the same resumable upload.
+#[derive(Debug)]
+pub struct UploadBusy(pub String);
+impl fmt::Display for UploadBusy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upload {} is being written by another request", self.0)
+    }
+}
+impl std::error::Error for UploadBusy {}
+
+#[derive(Debug)]
+pub struct LeaseLost(pub i32);
+impl fmt::Display for LeaseLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Review {} is not leased to you", self.0)
+    }
+}
+impl std::error::Error for LeaseLost {}
+
+pub fn error_status(e: &anyhow::Error) -> Status {
+    if e.is::<InvalidMeta>() {
+        return Status::UnprocessableEntity;
+    }
+    if e.is::<UploadExpired>() || e.is::<ImageExpired>() {
+        return Status::Gone;
+    }
+    if e.is::<OffsetMismatch>() || e.is::<LeaseLost>() {
+        return Status::Conflict;
+    }
+    if e.is::<UploadBusy>() {
+        return Status::Locked;
+    }
+    if e.is::<UnsupportedImage>() {
+        return Status::UnsupportedMediaType;
+    }
+    if e.is::<ImageTooLarge>() {
+        return Status::PayloadTooLarge;
+    }
+    if e.is::<DigestMismatch>() {
+        // "Checksum Mismatch" in tus
+        return Status::new(460);
+    }
+    if e.is::<BadRequest>() || e.is::<serde_json::Error>() || e.is::<chrono::ParseError>() {
+        return Status::BadRequest;
+    }
+    match e.downcast_ref::<DieselError>() {
+        Some(DieselError::NotFound) => Status::NotFound,
+        Some(DieselError::DatabaseError(
+            DatabaseErrorKind::UniqueViolation | DatabaseErrorKind::ForeignKeyViolation,
+            _,
+        )) => Status::Conflict,
+        _ => Status::InternalServerError,
+    }
+}
+
+pub fn error_response(e: anyhow::Error) -> (Status, String) {
+    if let Some(InvalidMeta(errors)) = e.downcast_ref() {
+        return (
+            Status::UnprocessableEntity,
+            serde_json::to_string(errors).unwrap_or_default(),
+        );
+    }
+    (error_status(&e), format!("{e:?}"))
+}
+
 #[get("/healthz")]
 pub fn healthz() -> (Status, ()) {
     (Status::Ok, ())
@@ -27,13 +180,249 @@ struct UploadMetaResponse {
     location_header: Header<'static>,
 }
 
+// The camera is either given explicitly, or named by the `camera_id`/`camera` field of the metadata.
+async fn find_camera(
+    pg_conn: &mut AsyncPgConnection,
+    camera_id: Option<i32>,
+    meta: &Value,
+) -> anyhow::Result<Camera> {
+    let query = cameras::table.select(Camera::as_select());
+    let meta_id = meta["camera_id"]
+        .as_i64()
+        .map(|x| i32::try_from(x).map_err(|_| BadRequest(format!("Camera id {x} out of range"))))
+        .transpose()?;
+    let camera = if let Some(id) = camera_id.or(meta_id) {
+        query.find(id).first(pg_conn).await.optional()?
+    } else if let Some(name) = meta["camera"].as_str() {
+        query
+            .filter(cameras::name.eq(name))
+            .first(pg_conn)
+            .await
+            .optional()?
+    } else {
+        Err(BadRequest("No camera specified".to_string()))?
+    };
+    Ok(camera.ok_or(BadRequest("Unknown camera".to_string()))?)
+}
+
+// For query parameters, which have no camera to tell the timezone.
+pub fn parse_time(time: &str) -> anyhow::Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc))
+}
+
+// Timestamps without offset are in the camera's local time. Missing means now.
+fn parse_captured_at(value: &Value, timezone: &str) -> anyhow::Result<DateTime<Utc>> {
+    let Some(value) = value.as_str() else {
+        return Ok(Utc::now());
+    };
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Ok(t.with_timezone(&Utc));
+    }
+    let tz: chrono_tz::Tz = timezone
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Bad timezone {timezone}: {e}"))?;
+    let local = NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f")?;
+    Ok(tz
+        .from_local_datetime(&local)
+        .earliest()
+        .ok_or(BadRequest(format!("{value} does not exist in {timezone}")))?
+        .with_timezone(&Utc))
+}
+
+// Everything about an upload that can be checked before receiving the image.
+async fn prepare_upload(
+    state: &StoreState,
+    camera_id: Option<i32>,
+    meta: &str,
+) -> anyhow::Result<PendingUpload> {
+    let metadata: Value = serde_json::from_str(meta)?;
+    let errors: Vec<_> = state
+        .meta_schema
+        .iter_errors(&metadata)
+        .map(|e| MetaError {
+            path: e.instance_path().to_string(),
+            message: e.to_string(),
+        })
+        .collect();
+    if !errors.is_empty() {
+        Err(InvalidMeta(errors))?;
+    }
+    let mut pg_conn = state.pg_pool.get().await?;
+    let camera = find_camera(&mut pg_conn, camera_id, &metadata).await?;
+    Ok(PendingUpload {
+        camera_id: camera.id,
+        captured_at: parse_captured_at(&metadata["captured_at"], &camera.timezone)?,
+        metadata,
+        upload_length: None,
+        expected_digest: None,
+    })
+}
+
+pub async fn save_pending(
+    state: &StoreState,
+    upload_id: &str,
+    pending: &PendingUpload,
+) -> anyhow::Result<()> {
+    let mut redis = state.redis_pool.aquire().await?;
+    redis
+        .set_ex(
+            upload_key(upload_id),
+            serde_json::to_string(pending)?,
+            state.upload_session_ttl,
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn load_pending(state: &StoreState, upload_id: &str) -> anyhow::Result<PendingUpload> {
+    let mut redis = state.redis_pool.aquire().await?;
+    let pending: Option<String> = redis.get(upload_key(upload_id)).await?;
+    let pending = pending.ok_or(UploadExpired(upload_id.to_string()))?;
+    Ok(serde_json::from_str(&pending)?)
+}
+
+// Checks the uploaded file and puts it into storage.
+async fn persist_image(state: &StoreState, file: &TempFile<'_>) -> anyhow::Result<StoredImage> {
+    let mut content = Vec::new();
+    file.open().await?.read_to_end(&mut content).await?;
+    let (content, stored) = process_image(content, &state.image_limits).await?;
+    put_image(state, &stored, content).await?;
+    Ok(stored)
+}
+
+// Noted before it is put, so a crash in between leaves nothing the sweeper does not know about.
+pub async fn put_image(
+    state: &StoreState,
+    stored: &StoredImage,
+    content: Vec<u8>,
+) -> anyhow::Result<()> {
+    let mut redis = state.redis_pool.aquire().await?;
+    redis
+        .zadd(PERSISTING_KEY, stored.name(), Utc::now().timestamp())
+        .await?;
+    state.storage.put(&stored.name(), content).await?;
+    Ok(())
+}
+
+// The rows of the files are in, the sweeper need not look at them. Failing only leaves it
+// more to check.
+async fn recorded(state: &StoreState, names: &[String]) {
+    let result: anyhow::Result<()> = try {
+        let mut redis = state.redis_pool.aquire().await?;
+        redis.zrem(PERSISTING_KEY, names).await?;
+    };
+    if let Err(e) = result {
+        error!("Unnoting persisted {names:?}: {e:?}");
+    }
+}
+
+// Files of a failed upload, unless an earlier upload of the same content has them.
+async fn remove_unrecorded(state: &StoreState, names: &[String]) -> anyhow::Result<()> {
+    let mut pg_conn = state.pg_pool.get().await?;
+    let recorded: Vec<String> = images::table
+        .filter(images::filename.eq_any(names))
+        .select(images::filename)
+        .load(&mut pg_conn)
+        .await?;
+    for name in names.iter().filter(|x| !recorded.contains(x)) {
+        state.storage.delete(name).await?;
+    }
+    Ok(())
+}
+
+// What the client did not tell about the image is taken from its EXIF.
+async fn new_image(
+    state: &StoreState,
+    upload_id: &str,
+    stored: &StoredImage,
+    pending: &PendingUpload,
+) -> anyhow::Result<NewImage> {
+    let mut metadata = pending.metadata.clone();
+    let mut captured_at = pending.captured_at;
+    if let (Some(exif), Some(fields)) = (&stored.exif, metadata.as_object_mut()) {
+        if let (None, Some(exif_captured_at)) = (fields.get("captured_at"), &exif.captured_at) {
+            let mut pg_conn = state.pg_pool.get().await?;
+            let timezone: String = cameras::table
+                .find(pending.camera_id)
+                .select(cameras::timezone)
+                .first(&mut pg_conn)
+                .await?;
+            let exif_captured_at = Value::String(exif_captured_at.clone());
+            captured_at = parse_captured_at(&exif_captured_at, &timezone)?;
+            fields.insert("captured_at".to_string(), exif_captured_at);
+        }
+        if !fields.contains_key("device") && (exif.make.is_some() || exif.model.is_some()) {
+            fields.insert(
+                "device".to_string(),
+                json!({ "make": exif.make, "model": exif.model }),
+            );
+        }
+        if let (false, Some((latitude, longitude))) = (fields.contains_key("gps"), exif.gps) {
+            fields.insert(
+                "gps".to_string(),
+                json!({ "latitude": latitude, "longitude": longitude }),
+            );
+        }
+    }
+    Ok(NewImage {
+        filename: stored.name(),
+        digest: stored.digest.clone(),
+        metadata: Some(metadata),
+        camera_id: Some(pending.camera_id),
+        captured_at,
+        upload_id: upload_id.to_string(),
+        width: stored.width.try_into().ok(),
+        height: stored.height.try_into().ok(),
+        format: Some(format_name(stored.format)),
+    })
+}
+
+// Records an image already in storage and closes its upload session.
+pub async fn finish_upload(
+    state: &StoreState,
+    upload_id: &str,
+    stored: &StoredImage,
+    pending: &PendingUpload,
+) -> anyhow::Result<()> {
+    let mut pg_conn = state.pg_pool.get().await?;
+    let image: Image = diesel::insert_into(images::table)
+        .values(new_image(state, upload_id, stored, pending).await?)
+        .returning(Image::as_returning())
+        .get_result(&mut pg_conn)
+        .await?;
+    recorded(state, &[stored.name()]).await;
+
+    let mut redis = state.redis_pool.aquire().await?;
+    redis.del::<String, String>(upload_key(upload_id)).await?;
+    uploaded(state, &image).await;
+    Ok(())
+}
+
+async fn uploaded(state: &StoreState, image: &Image) {
+    let data = json!({
+        "image_id": image.id,
+        "camera_id": image.camera_id,
+        "captured_at": image.captured_at,
+        "upload_id": image.upload_id,
+    });
+    streaming::publish(&state.redis_pool, IMAGE_UPLOADED, data).await;
+}
+
 #[instrument]
-#[post("/upload_meta", format = "application/json", data = "<meta>")]
-pub async fn upload_meta(state: &State<StoreState>, meta: String) -> UploadMetaResponse {
+#[post(
+    "/upload_meta?<camera_id>",
+    format = "application/json",
+    data = "<meta>"
+)]
+pub async fn upload_meta(
+    state: &State<StoreState>,
+    camera_id: Option<i32>,
+    meta: String,
+) -> UploadMetaResponse {
     let upload_id = state.get_id();
     let result: anyhow::Result<()> = try {
-        let mut redis = state.redis_pool.aquire().await?;
-        redis.set(&upload_id, meta).await?;
+        let pending = prepare_upload(state, camera_id, &meta).await?;
+        save_pending(state, &upload_id, &pending).await?;
     };
     match result {
         Ok(_) => {
@@ -47,7 +436,7 @@ pub async fn upload_meta(state: &State<StoreState>, meta: String) -> UploadMetaR
             }
         }
         Err(e) => UploadMetaResponse {
-            inner: (Status::InternalServerError, format!("{e:?}")),
+            inner: error_response(e),
             location_header: Header::new("", ""),
         },
     }
@@ -58,35 +447,235 @@ pub async fn upload_meta(state: &State<StoreState>, meta: String) -> UploadMetaR
 pub async fn upload_image(
     state: &State<StoreState>,
     upload_id: String,
-    mut file: TempFile<'_>,
+    file: TempFile<'_>,
 ) -> (Status, String) {
     let result: anyhow::Result<()> = try {
-        let mut redis = state.redis_pool.aquire().await?;
-        let meta: String = redis.get(&upload_id).await?;
-        let meta: serde_json::Value = serde_json::from_str(&meta)?;
+        let pending = load_pending(state, &upload_id).await?;
+        let stored = persist_image(state, &file).await?;
+        finish_upload(state, &upload_id, &stored, &pending).await?;
+    };
+    match result {
+        Ok(_) => (Status::Ok, String::new()),
+        Err(e) => error_response(e),
+    }
+}
+
+// Images at `max_image_bytes` one multipart upload has room for.
+pub const MAX_UPLOAD_IMAGES: u64 = 16;
 
-        let filename = state.image_folder.clone().join(&upload_id);
-        file.persist_to(&filename).await?;
+#[derive(Debug, FromForm)]
+pub struct MultipartUpload<'r> {
+    meta: String,
+    image: Vec<TempFile<'r>>,
+}
 
-        let mut hasher = Sha3_224::new();
-        let content = tokio::fs::read(filename).await?;
-        hasher.update(&content);
-        let digest = hasher.finalize();
+// `upload_meta` and `upload_image` in one request.
+// All images share the metadata, and either all or none are stored.
+#[instrument]
+#[post("/?<camera_id>", format = "multipart/form-data", data = "<upload>")]
+pub async fn upload(
+    state: &State<StoreState>,
+    camera_id: Option<i32>,
+    upload: Form<MultipartUpload<'_>>,
+) -> Result<Json<Vec<i32>>, (Status, String)> {
+    let mut persisted = Vec::new();
+    let result: anyhow::Result<Vec<i32>> = try {
+        if upload.image.is_empty() {
+            Err(BadRequest("No image part".to_string()))?;
+        }
+        let pending = prepare_upload(state, camera_id, &upload.meta).await?;
+        let mut rows = Vec::new();
+        for file in upload.image.iter() {
+            let upload_id = state.get_id();
+            let stored = persist_image(state, file).await?;
+            persisted.push(stored.name());
+            rows.push(new_image(state, &upload_id, &stored, &pending).await?);
+        }
 
         let mut pg_conn = state.pg_pool.get().await?;
-        diesel::insert_into(images::table)
-            .values((
-                images::filename.eq(&upload_id),
-                images::digest.eq(digest.as_slice()),
-                images::metadata.eq(&meta),
-            ))
-            .execute(&mut pg_conn)
+        let images: Vec<Image> = diesel::insert_into(images::table)
+            .values(&rows)
+            .returning(Image::as_returning())
+            .get_results(&mut pg_conn)
             .await?;
+        recorded(state, &persisted).await;
+        for image in &images {
+            uploaded(state, image).await;
+        }
+        images.into_iter().map(|x| x.id).collect()
+    };
+    if result.is_err() {
+        if let Err(e) = remove_unrecorded(state, &persisted).await {
+            error!("Cleaning up failed upload: {e:?}");
+        }
+    }
+    result.map(Json).map_err(error_response)
+}
+
+#[instrument]
+#[get("/<upload_id>")]
+pub async fn upload_status(
+    state: &State<StoreState>,
+    upload_id: String,
+) -> Result<Json<UploadStatus>, (Status, String)> {
+    let result: anyhow::Result<UploadStatus> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        let image_id: Option<i32> = images::table
+            .filter(images::upload_id.eq(&upload_id))
+            .select(images::id)
+            .first(&mut pg_conn)
+            .await
+            .optional()?;
+        match image_id {
+            Some(image_id) => UploadStatus::Completed { image_id },
+            None => {
+                let mut redis = state.redis_pool.aquire().await?;
+                // -2 for no such key, -1 for a key without expiry.
+                let ttl: i64 = redis.ttl(upload_key(&upload_id)).await?;
+                if ttl == -2 {
+                    UploadStatus::Expired
+                } else {
+                    UploadStatus::Pending {
+                        expires_in: (ttl >= 0).then_some(ttl),
+                    }
+                }
+            }
+        }
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[instrument]
+#[get("/")]
+pub async fn list_cameras(
+    state: &State<StoreState>,
+) -> Result<Json<Vec<Camera>>, (Status, String)> {
+    let result: anyhow::Result<Vec<Camera>> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        cameras::table
+            .select(Camera::as_select())
+            .order(cameras::id)
+            .load(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[instrument]
+#[get("/<id>")]
+pub async fn get_camera(
+    state: &State<StoreState>,
+    id: i32,
+) -> Result<Json<Camera>, (Status, String)> {
+    let result: anyhow::Result<Camera> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        cameras::table
+            .find(id)
+            .select(Camera::as_select())
+            .first(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+fn check_timezone(camera: &NewCamera) -> anyhow::Result<()> {
+    camera
+        .timezone
+        .parse::<chrono_tz::Tz>()
+        .map_err(|e| BadRequest(format!("Bad timezone {}: {e}", camera.timezone)))?;
+    Ok(())
+}
+
+#[instrument]
+#[post("/", format = "application/json", data = "<camera>")]
+pub async fn create_camera(
+    state: &State<StoreState>,
+    camera: Json<NewCamera>,
+) -> Result<Json<Camera>, (Status, String)> {
+    let result: anyhow::Result<Camera> = try {
+        check_timezone(&camera)?;
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::insert_into(cameras::table)
+            .values(camera.into_inner())
+            .returning(Camera::as_returning())
+            .get_result(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
 
-        redis.del::<String, String>(upload_id).await?;
+#[instrument]
+#[put("/<id>", format = "application/json", data = "<camera>")]
+pub async fn update_camera(
+    state: &State<StoreState>,
+    id: i32,
+    camera: Json<NewCamera>,
+) -> Result<Json<Camera>, (Status, String)> {
+    let result: anyhow::Result<Camera> = try {
+        check_timezone(&camera)?;
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::update(cameras::table.find(id))
+            .set(camera.into_inner())
+            .returning(Camera::as_returning())
+            .get_result(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+// Refused with 409 while images still refer to the camera.
+#[instrument]
+#[delete("/<id>")]
+pub async fn delete_camera(state: &State<StoreState>, id: i32) -> (Status, String) {
+    let result: anyhow::Result<usize> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::delete(cameras::table.find(id))
+            .execute(&mut pg_conn)
+            .await?
     };
     match result {
+        Ok(0) => (Status::NotFound, String::new()),
         Ok(_) => (Status::Ok, String::new()),
-        Err(e) => (Status::InternalServerError, format!("{e:?}")),
+        Err(e) => error_response(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captured_at_with_offset_or_in_camera_time() {
+        let expected = parse_time("2024-05-01T04:30:00Z").unwrap();
+        let with_offset = json!("2024-05-01T12:30:00+08:00");
+        assert_eq!(parse_captured_at(&with_offset, "UTC").unwrap(), expected);
+        let local = json!("2024-05-01T12:30:00");
+        assert_eq!(
+            parse_captured_at(&local, "Asia/Shanghai").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn default_schema_leaves_captured_at_to_exif() {
+        let schema: Value = serde_json::from_str(include_str!("../meta_schema.json")).unwrap();
+        let validator = jsonschema::validator_for(&schema).unwrap();
+        assert!(validator.is_valid(&json!({ "camera": "porch" })));
+        assert!(!validator.is_valid(&json!({ "captured_at": "2024-05-01T12:30:00" })));
+    }
+
+    #[test]
+    fn captured_at_missing_is_now() {
+        let before = Utc::now();
+        let captured_at = parse_captured_at(&Value::Null, "UTC").unwrap();
+        assert!(before <= captured_at && captured_at <= Utc::now());
+    }
+
+    #[test]
+    fn captured_at_skipped_by_daylight_saving_is_refused() {
+        let skipped = json!("2024-03-10T02:30:00");
+        let e = parse_captured_at(&skipped, "America/New_York").unwrap_err();
+        assert!(e.is::<BadRequest>());
+        assert!(parse_captured_at(&skipped, "Nowhere/Special").is_err());
     }
 }
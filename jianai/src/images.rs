@@ -0,0 +1,436 @@
+// Serving images to dashboards.
+use crate::app_state::StoreState;
+use crate::checking::QUARANTINE_FOLDER;
+use crate::handlers::*;
+use crate::ingest::{hex, load_oriented};
+use crate::model::{Image, NewDeletion, SegmentWithTag};
+use crate::retaining::{unarchive, CROP_FOLDER};
+use crate::schema::*;
+use crate::sessioning::unsession;
+use ab_glyph::FontArc;
+use diesel::{
+    result::Error as DieselError, ExpressionMethods, PgJsonbExpressionMethods, QueryDsl,
+    SelectableHelper,
+};
+use diesel_async::{scoped_futures::ScopedFutureExt, AsyncConnection, RunQueryDsl};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use imageproc::{
+    drawing::{draw_filled_rect_mut, draw_hollow_rect_mut, draw_text_mut, text_size},
+    rect::Rect,
+};
+use rocket::{
+    http::{ContentType, Header, Status},
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+    serde::json::Json,
+    *,
+};
+use serde_json::Value;
+use std::{collections::HashSet, io::Cursor};
+use tokio::task::spawn_blocking;
+use tracing::instrument;
+
+// In storage, one folder per size.
+pub const THUMBNAIL_FOLDER: &str = ".thumbnails";
+
+#[derive(Debug)]
+pub struct IfNoneMatch(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(IfNoneMatch(
+            req.headers().get_one("If-None-Match").map(str::to_string),
+        ))
+    }
+}
+
+#[derive(Responder)]
+pub enum CachedImage {
+    #[response(status = 200, content_type = "image/jpeg")]
+    Image(Vec<u8>, Header<'static>, Header<'static>),
+    #[response(status = 304)]
+    NotModified((), Header<'static>, Header<'static>),
+}
+impl CachedImage {
+    // Images never change once stored, so anything derived from the digest can be cached forever.
+    pub async fn new<F>(
+        etag: String,
+        if_none_match: &IfNoneMatch,
+        content: F,
+    ) -> anyhow::Result<CachedImage>
+    where
+        F: std::future::Future<Output = anyhow::Result<Vec<u8>>>,
+    {
+        let etag_header = Header::new("ETag", etag.clone());
+        let cache_control = Header::new("Cache-Control", "public, max-age=31536000, immutable");
+        if if_none_match.0.as_deref() == Some(&etag) {
+            Ok(CachedImage::NotModified((), etag_header, cache_control))
+        } else {
+            Ok(CachedImage::Image(
+                content.await?,
+                etag_header,
+                cache_control,
+            ))
+        }
+    }
+}
+
+pub async fn find_image(state: &StoreState, id: i32) -> anyhow::Result<Image> {
+    let mut pg_conn = state.pg_pool.get().await?;
+    Ok(images::table
+        .find(id)
+        .select(Image::as_select())
+        .first(&mut pg_conn)
+        .await?)
+}
+
+pub fn thumbnail_name(size: u32, filename: &str) -> String {
+    format!("{THUMBNAIL_FOLDER}/{size}/{filename}.jpg")
+}
+
+pub fn encode_jpeg(image: DynamicImage) -> anyhow::Result<Vec<u8>> {
+    let mut content = Cursor::new(Vec::new());
+    DynamicImage::from(image.to_rgb8()).write_to(&mut content, ImageFormat::Jpeg)?;
+    Ok(content.into_inner())
+}
+
+// Made on first request, then kept.
+async fn thumbnail(state: &StoreState, image: &Image, size: u32) -> anyhow::Result<Vec<u8>> {
+    let name = thumbnail_name(size, &image.filename);
+    if let Some(content) = state.storage.try_get(&name).await? {
+        return Ok(content);
+    }
+    if image.expired_at.is_some() {
+        Err(ImageExpired(image.id))?;
+    }
+    let original = state.storage.get(&image.filename).await?;
+    let content =
+        spawn_blocking(move || encode_jpeg(load_oriented(&original)?.thumbnail(size, size)))
+            .await??;
+    state.storage.put(&name, content.clone()).await?;
+    Ok(content)
+}
+
+// The longer side is `size`, which defaults to the smallest configured one.
+#[instrument]
+#[get("/<id>/thumbnail?<size>")]
+pub async fn get_thumbnail(
+    state: &State<StoreState>,
+    id: i32,
+    size: Option<u32>,
+    if_none_match: IfNoneMatch,
+) -> Result<CachedImage, (Status, String)> {
+    let result: anyhow::Result<CachedImage> = try {
+        let size = match size {
+            Some(size) if state.thumbnail_sizes.contains(&size) => size,
+            Some(size) => Err(BadRequest(format!(
+                "Size {size} is not one of {:?}",
+                state.thumbnail_sizes
+            )))?,
+            None => *state
+                .thumbnail_sizes
+                .iter()
+                .min()
+                .ok_or(BadRequest("No thumbnail sizes configured".to_string()))?,
+        };
+        let image = find_image(state, id).await?;
+        CachedImage::new(
+            format!("\"{}-{size}\"", hex(&image.digest)),
+            &if_none_match,
+            thumbnail(state, &image, size),
+        )
+        .await?
+    };
+    result.map_err(error_response)
+}
+
+const PALETTE: [Rgb<u8>; 8] = [
+    Rgb([230, 25, 75]),
+    Rgb([60, 180, 75]),
+    Rgb([255, 225, 25]),
+    Rgb([0, 130, 200]),
+    Rgb([245, 130, 48]),
+    Rgb([145, 30, 180]),
+    Rgb([70, 240, 240]),
+    Rgb([240, 50, 230]),
+];
+const UNKNOWN: Rgb<u8> = Rgb([128, 128, 128]);
+
+// A human tag wins over what the identifier thinks. Cats keep their color across images.
+fn annotate(image: &mut RgbImage, segments: &[SegmentWithTag], font: Option<&FontArc>) {
+    let thickness = (image.height() / 400).max(2) as i32;
+    let scale = (image.height() / 40).max(16) as f32;
+    for segment in segments {
+        let tag = segment
+            .tagged_as
+            .as_ref()
+            .or(segment.identified_as.as_ref());
+        let color = tag.map_or(UNKNOWN, |x| {
+            PALETTE[x.id.unsigned_abs() as usize % PALETTE.len()]
+        });
+        let x = segment.bounding_box.point1.x as i32;
+        let y = segment.bounding_box.point1.y as i32;
+        let width = segment.bounding_box.width().max(1.0) as u32;
+        let height = segment.bounding_box.height().max(1.0) as u32;
+        for i in 0..thickness {
+            draw_hollow_rect_mut(
+                image,
+                Rect::at(x - i, y - i).of_size(width + 2 * i as u32, height + 2 * i as u32),
+                color,
+            );
+        }
+
+        let Some(font) = font else {
+            continue;
+        };
+        let mut label = tag.map_or("?".to_string(), |x| x.tag.clone());
+        if let Some(confidence) = segment.confidence {
+            label = format!("{label} {confidence:.2}");
+        }
+        if segment.low_quality {
+            label = format!("{label} (low quality)");
+        }
+        let (text_width, text_height) = text_size(scale, font, &label);
+        let text_y = (y - thickness - text_height as i32).max(0);
+        draw_filled_rect_mut(
+            image,
+            Rect::at(x - thickness, text_y).of_size(text_width + 4, text_height),
+            color,
+        );
+        draw_text_mut(
+            image,
+            Rgb([0, 0, 0]),
+            x - thickness + 2,
+            text_y,
+            scale,
+            font,
+            &label,
+        );
+    }
+}
+
+// Every segment boxed and labeled. Low quality ones are left out unless asked for.
+#[instrument]
+#[get("/<id>/annotated?<low_quality>")]
+pub async fn get_annotated(
+    state: &State<StoreState>,
+    id: i32,
+    low_quality: Option<bool>,
+) -> Result<(ContentType, Vec<u8>), (Status, String)> {
+    let result: anyhow::Result<Vec<u8>> = try {
+        let image = find_image(state, id).await?;
+        let mut pg_conn = state.pg_pool.get().await?;
+        let segments: Vec<_> = SegmentWithTag::for_images(&mut pg_conn, &[id])
+            .await?
+            .into_iter()
+            .filter(|x| low_quality.unwrap_or(false) || !x.low_quality)
+            .collect();
+        drop(pg_conn);
+        if image.expired_at.is_some() {
+            Err(ImageExpired(image.id))?;
+        }
+        let original = state.storage.get(&image.filename).await?;
+        let font = state.label_font.clone();
+        spawn_blocking(move || {
+            let mut canvas = load_oriented(&original)?.to_rgb8();
+            annotate(&mut canvas, &segments, font.as_ref());
+            encode_jpeg(DynamicImage::from(canvas))
+        })
+        .await??
+    };
+    result
+        .map(|x| (ContentType::JPEG, x))
+        .map_err(error_response)
+}
+
+// Who is deleting or reviewing, for the record.
+#[derive(Debug)]
+pub struct User(pub String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.headers().get_one("X-User") {
+            Some(user) if !user.is_empty() => Outcome::Success(User(user.to_string())),
+            _ => Outcome::Error((Status::BadRequest, ())),
+        }
+    }
+}
+
+// Rows go in one transaction, each leaving a record in `deletions`, along with the feeding
+// sessions the images were in. Files go after it, so a failure leaves orphans for `fsck`
+// rather than rows without files. That is the original, thumbnails of every size ever made,
+// a copy `fsck` quarantined, crops, and the images in retention archives.
+async fn delete_images(
+    state: &StoreState,
+    ids: Vec<i32>,
+    user: &User,
+    reason: Option<String>,
+) -> anyhow::Result<Vec<i32>> {
+    let mut pg_conn = state.pg_pool.get().await?;
+    let deleted_by = user.0.clone();
+    let recorded_reason = reason.clone();
+    let (deleted, segment_ids): (Vec<Image>, Vec<i32>) = pg_conn
+        .transaction(|pg_conn| {
+            (async move {
+                let segment_ids: Vec<i32> =
+                    diesel::delete(segments::table.filter(segments::image_id.eq_any(&ids)))
+                        .returning(segments::id)
+                        .get_results(pg_conn)
+                        .await?;
+                let deleted: Vec<Image> =
+                    diesel::delete(images::table.filter(images::id.eq_any(&ids)))
+                        .returning(Image::as_returning())
+                        .get_results(pg_conn)
+                        .await?;
+                unsession(pg_conn, &deleted).await?;
+                let records: Vec<NewDeletion> = deleted
+                    .iter()
+                    .map(|x| NewDeletion {
+                        image_id: x.id,
+                        filename: x.filename.clone(),
+                        digest: x.digest.clone(),
+                        camera_id: x.camera_id,
+                        captured_at: x.captured_at,
+                        deleted_by: deleted_by.clone(),
+                        reason: recorded_reason.clone(),
+                    })
+                    .collect();
+                diesel::insert_into(deletions::table)
+                    .values(&records)
+                    .execute(pg_conn)
+                    .await?;
+                Ok((deleted, segment_ids)) as Result<_, diesel::result::Error>
+            })
+            .scope_boxed()
+        })
+        .await?;
+    for image in &deleted {
+        info!(
+            "{} deleted image {} ({}): {}",
+            user.0,
+            image.id,
+            image.filename,
+            reason.as_deref().unwrap_or("no reason given")
+        );
+    }
+
+    // Identical uploads share a file.
+    let filenames: Vec<&String> = deleted.iter().map(|x| &x.filename).collect();
+    let shared: Vec<String> = images::table
+        .filter(images::filename.eq_any(&filenames))
+        .select(images::filename)
+        .load(&mut pg_conn)
+        .await?;
+    // Sizes no longer configured may still have thumbnails.
+    let sizes = state.storage.folders(THUMBNAIL_FOLDER).await?;
+    for filename in filenames.into_iter().filter(|x| !shared.contains(x)) {
+        state.storage.delete(filename).await?;
+        state
+            .storage
+            .delete(&format!("{QUARANTINE_FOLDER}/{filename}"))
+            .await?;
+        for size in &sizes {
+            state
+                .storage
+                .delete(&format!("{size}/{filename}.jpg"))
+                .await?;
+        }
+    }
+    for segment_id in segment_ids {
+        state
+            .storage
+            .delete(&format!("{CROP_FOLDER}/{segment_id}.jpg"))
+            .await?;
+    }
+    // Only originals removed by retention were archived.
+    let archived: HashSet<i32> = deleted
+        .iter()
+        .filter(|x| x.expired_at.is_some())
+        .map(|x| x.id)
+        .collect();
+    if !archived.is_empty() {
+        unarchive(&state.storage, &archived).await?;
+    }
+    Ok(deleted.into_iter().map(|x| x.id).collect())
+}
+
+// The image is gone along with its file, segments and thumbnails. Needs `X-User`.
+#[instrument]
+#[delete("/<id>?<reason>")]
+pub async fn delete_image(
+    state: &State<StoreState>,
+    id: i32,
+    reason: Option<String>,
+    user: User,
+) -> (Status, String) {
+    let result: anyhow::Result<()> = try {
+        if delete_images(state, vec![id], &user, reason)
+            .await?
+            .is_empty()
+        {
+            Err(DieselError::NotFound)?;
+        }
+    };
+    match result {
+        Ok(_) => (Status::NoContent, String::new()),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Debug, FromForm)]
+pub struct ImageFilter {
+    camera_id: Option<i32>,
+    // RFC 3339, inclusive.
+    from: Option<String>,
+    // RFC 3339, exclusive.
+    to: Option<String>,
+    // JSON the metadata contains.
+    metadata: Option<String>,
+    reason: Option<String>,
+}
+
+// Like `delete_image` for all images matching the filter, which must not be empty.
+#[instrument]
+#[delete("/?<filter..>")]
+pub async fn delete_matching(
+    state: &State<StoreState>,
+    filter: ImageFilter,
+    user: User,
+) -> Result<Json<Vec<i32>>, (Status, String)> {
+    let result: anyhow::Result<Vec<i32>> = try {
+        if filter.camera_id.is_none()
+            && filter.from.is_none()
+            && filter.to.is_none()
+            && filter.metadata.is_none()
+        {
+            Err(BadRequest(
+                "Give at least one of camera_id, from, to and metadata".to_string(),
+            ))?;
+        }
+        let mut query = images::table.select(images::id).into_boxed();
+        if let Some(camera_id) = filter.camera_id {
+            query = query.filter(images::camera_id.eq(camera_id));
+        }
+        if let Some(from) = &filter.from {
+            query = query.filter(images::captured_at.ge(parse_time(from)?));
+        }
+        if let Some(to) = &filter.to {
+            query = query.filter(images::captured_at.lt(parse_time(to)?));
+        }
+        if let Some(metadata) = &filter.metadata {
+            let metadata: Value = serde_json::from_str(metadata)?;
+            query = query.filter(images::metadata.contains(metadata));
+        }
+        let mut pg_conn = state.pg_pool.get().await?;
+        let ids: Vec<i32> = query.load(&mut pg_conn).await?;
+        drop(pg_conn);
+        delete_images(state, ids, &user, filter.reason.clone()).await?
+    };
+    result.map(Json).map_err(error_response)
+}
@@ -0,0 +1,204 @@
+use crate::handlers::{ImageTooLarge, UnsupportedImage};
+use anyhow::Result;
+use chrono::NaiveDateTime;
+use exif::{Exif, In, Tag};
+use image::{
+    metadata::Orientation, DynamicImage, ImageDecoder, ImageFormat, ImageReader, ImageResult,
+};
+use sha3::{Digest, Sha3_224};
+use std::io::Cursor;
+use tokio::task::spawn_blocking;
+
+#[derive(Debug, Clone)]
+pub struct ImageLimits {
+    pub max_bytes: u64,
+    pub max_dimension: u32,
+    // Re-encode uploads in other formats to this one.
+    pub canonical_format: Option<ImageFormat>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredImage {
+    pub digest: Vec<u8>,
+    // As displayed, that is after EXIF orientation.
+    pub width: u32,
+    pub height: u32,
+    pub format: ImageFormat,
+    pub exif: Option<ExifInfo>,
+}
+impl StoredImage {
+    pub fn name(&self) -> String {
+        content_name(&self.digest, self.format)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExifInfo {
+    // RFC 3339 if the offset is recorded, otherwise local time of the camera.
+    pub captured_at: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    // Latitude and longitude.
+    pub gps: Option<(f64, f64)>,
+}
+
+pub fn digest(content: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha3_224::new();
+    hasher.update(content);
+    hasher.finalize().to_vec()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+// Named after the content, in two levels of folders so none gets too big: `ab/cd/abcd….jpg`.
+pub fn content_name(digest: &[u8], format: ImageFormat) -> String {
+    let hex = hex(digest);
+    let extension = format.extensions_str().first().unwrap_or(&"bin");
+    format!("{}/{}/{hex}.{extension}", &hex[..2], &hex[2..4])
+}
+
+pub fn format_name(format: ImageFormat) -> String {
+    format!("{format:?}").to_lowercase()
+}
+
+// Checks the upload is an image within limits, re-encoding it if asked.
+// Returns what to store along with what we learned about it.
+pub async fn process_image(
+    content: Vec<u8>,
+    limits: &ImageLimits,
+) -> Result<(Vec<u8>, StoredImage)> {
+    let size = content.len();
+    if size as u64 > limits.max_bytes {
+        Err(ImageTooLarge(format!("{size} bytes")))?;
+    }
+    let limits = limits.clone();
+    let decoded = spawn_blocking(move || decode(content, &limits)).await??;
+    let stored = StoredImage {
+        digest: digest(&decoded.content),
+        width: decoded.width,
+        height: decoded.height,
+        format: decoded.format,
+        exif: decoded.exif,
+    };
+    Ok((decoded.content, stored))
+}
+
+struct Decoded {
+    content: Vec<u8>,
+    width: u32,
+    height: u32,
+    format: ImageFormat,
+    exif: Option<ExifInfo>,
+}
+
+// Decodes with EXIF orientation applied, which is how users see the image.
+pub fn load_oriented(content: &[u8]) -> ImageResult<DynamicImage> {
+    let mut decoder = ImageReader::new(Cursor::new(content))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        exif::Value::Ascii(values) => values
+            .first()
+            .map(|x| String::from_utf8_lossy(x).trim().to_string()),
+        _ => None,
+    }
+}
+
+// Degrees, minutes and seconds, with the reference telling the hemisphere.
+fn coordinate(exif: &Exif, tag: Tag, reference: Tag, negative: &str) -> Option<f64> {
+    let exif::Value::Rational(values) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    let [degrees, minutes, seconds] = values.as_slice() else {
+        return None;
+    };
+    let value = degrees.to_f64() + minutes.to_f64() / 60.0 + seconds.to_f64() / 3600.0;
+    if ascii(exif, reference).as_deref() == Some(negative) {
+        Some(-value)
+    } else {
+        Some(value)
+    }
+}
+
+fn read_exif(content: &[u8]) -> Option<ExifInfo> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(content))
+        .ok()?;
+    let captured_at = ascii(&exif, Tag::DateTimeOriginal)
+        .and_then(|x| NaiveDateTime::parse_from_str(&x, "%Y:%m:%d %H:%M:%S").ok())
+        .map(|x| {
+            let local = x.format("%Y-%m-%dT%H:%M:%S").to_string();
+            match ascii(&exif, Tag::OffsetTimeOriginal) {
+                Some(offset) => format!("{local}{offset}"),
+                None => local,
+            }
+        });
+    Some(ExifInfo {
+        captured_at,
+        make: ascii(&exif, Tag::Make),
+        model: ascii(&exif, Tag::Model),
+        gps: coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S").zip(coordinate(
+            &exif,
+            Tag::GPSLongitude,
+            Tag::GPSLongitudeRef,
+            "W",
+        )),
+    })
+}
+
+fn decode(content: Vec<u8>, limits: &ImageLimits) -> Result<Decoded> {
+    let format = ImageReader::new(Cursor::new(&content))
+        .with_guessed_format()?
+        .format()
+        .ok_or(UnsupportedImage("Unknown format".to_string()))?;
+    let mut decoder = ImageReader::with_format(Cursor::new(&content), format)
+        .into_decoder()
+        .map_err(|e| UnsupportedImage(e.to_string()))?;
+    // Dimensions come from the header, so oversized images are refused before decoding.
+    let (width, height) = decoder.dimensions();
+    if width.max(height) > limits.max_dimension {
+        Err(ImageTooLarge(format!("{width}x{height}")))?;
+    }
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image =
+        DynamicImage::from_decoder(decoder).map_err(|e| UnsupportedImage(e.to_string()))?;
+    image.apply_orientation(orientation);
+    let exif = read_exif(&content);
+
+    match limits.canonical_format {
+        Some(canonical) if canonical != format => {
+            // The orientation is already applied, as the EXIF does not survive re-encoding.
+            // JPEG has no alpha channel.
+            let image = if canonical == ImageFormat::Jpeg {
+                DynamicImage::from(image.to_rgb8())
+            } else {
+                image
+            };
+            let mut reencoded = Cursor::new(Vec::new());
+            image.write_to(&mut reencoded, canonical)?;
+            Ok(Decoded {
+                content: reencoded.into_inner(),
+                width: image.width(),
+                height: image.height(),
+                format: canonical,
+                exif,
+            })
+        }
+        _ => Ok(Decoded {
+            content,
+            width: image.width(),
+            height: image.height(),
+            format,
+            exif,
+        }),
+    }
+}
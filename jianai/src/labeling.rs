@@ -0,0 +1,262 @@
+// Tagging segments by hand, through the API or the page at `/ui`.
+use crate::app_state::StoreState;
+use crate::handlers::*;
+use crate::images::find_image;
+use crate::model::{NewTag, Segment, SegmentLabel, SegmentWithTag, Tag};
+use crate::retaining::{crop, CROP_FOLDER};
+use crate::schema::*;
+use crate::streaming;
+use crate::webhooking::{self, CAT_IDENTIFIED};
+use chrono::{DateTime, Utc};
+use diesel::{BoolExpressionMethods, ExpressionMethods, QueryDsl, QueryResult, SelectableHelper};
+use diesel_async::{
+    scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection, RunQueryDsl,
+};
+use rocket::{
+    http::{ContentType, Status},
+    response::content::RawHtml,
+    serde::json::Json,
+    *,
+};
+use serde_json::{json, Value};
+use tokio::task::spawn_blocking;
+use tracing::{error, instrument};
+
+// Segments per page of `list_segments`, and the most one may ask for.
+const PAGE: i64 = 60;
+const MAX_PAGE: i64 = 500;
+
+#[get("/")]
+pub fn label_page() -> RawHtml<&'static str> {
+    RawHtml(include_str!("../ui/label.html"))
+}
+
+#[instrument]
+#[get("/")]
+pub async fn list_tags(state: &State<StoreState>) -> Result<Json<Vec<Tag>>, (Status, String)> {
+    let result: anyhow::Result<Vec<Tag>> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        tags::table
+            .select(Tag::as_select())
+            .order(tags::id)
+            .load(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[instrument]
+#[post("/", format = "application/json", data = "<tag>")]
+pub async fn create_tag(
+    state: &State<StoreState>,
+    tag: Json<NewTag>,
+) -> Result<Json<Tag>, (Status, String)> {
+    let result: anyhow::Result<Tag> = try {
+        if tag.tag.trim().is_empty() {
+            Err(BadRequest("Empty tag".to_string()))?;
+        }
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::insert_into(tags::table)
+            .values(tag.into_inner())
+            .returning(Tag::as_returning())
+            .get_result(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[derive(Debug, Clone, Copy, FromFormField)]
+pub enum Review {
+    // Neither tagged nor marked low quality.
+    Untagged,
+    // The identifier thinks it is another cat than the human does.
+    Disagreement,
+    All,
+}
+
+// Newest first, `before` a segment id for the next page.
+// Segments of expired images are only there when tagged, as only those have crops.
+#[instrument]
+#[get("/?<review>&<camera_id>&<before>&<limit>")]
+pub async fn list_segments(
+    state: &State<StoreState>,
+    review: Option<Review>,
+    camera_id: Option<i32>,
+    before: Option<i32>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<SegmentWithTag>>, (Status, String)> {
+    let result: anyhow::Result<Vec<SegmentWithTag>> = try {
+        let mut query = segments::table
+            .inner_join(images::table)
+            .filter(
+                images::expired_at
+                    .is_null()
+                    .or(segments::tagged_as.is_not_null()),
+            )
+            .select(Segment::as_select())
+            .order(segments::id.desc())
+            .limit(limit.unwrap_or(PAGE).clamp(1, MAX_PAGE))
+            .into_boxed();
+        match review.unwrap_or(Review::Untagged) {
+            Review::Untagged => {
+                query = query
+                    .filter(segments::tagged_as.is_null())
+                    .filter(segments::low_quality.eq(false));
+            }
+            Review::Disagreement => {
+                query = query.filter(segments::identified_as.ne(segments::tagged_as));
+            }
+            Review::All => {}
+        }
+        if let Some(camera_id) = camera_id {
+            query = query.filter(images::camera_id.eq(camera_id));
+        }
+        if let Some(before) = before {
+            query = query.filter(segments::id.lt(before));
+        }
+        let mut pg_conn = state.pg_pool.get().await?;
+        let segments = query.load(&mut pg_conn).await?;
+        SegmentWithTag::with_tags(&mut pg_conn, segments).await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+// From the original, or what retention kept of it.
+#[instrument]
+#[get("/<id>/crop")]
+pub async fn get_crop(
+    state: &State<StoreState>,
+    id: i32,
+) -> Result<(ContentType, Vec<u8>), (Status, String)> {
+    let result: anyhow::Result<Vec<u8>> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        let segment: Segment = segments::table
+            .find(id)
+            .select(Segment::as_select())
+            .first(&mut pg_conn)
+            .await?;
+        drop(pg_conn);
+        let image = find_image(state, segment.image_id).await?;
+        if image.expired_at.is_some() {
+            let kept = state
+                .storage
+                .try_get(&format!("{CROP_FOLDER}/{id}.jpg"))
+                .await?;
+            kept.ok_or(ImageExpired(image.id))?
+        } else {
+            let original = state.storage.get(&image.filename).await?;
+            let boxes = vec![(id, segment.bounding_box)];
+            let mut cropped = spawn_blocking(move || crop(&original, boxes)).await??;
+            cropped.remove(0).1
+        }
+    };
+    result
+        .map(|x| (ContentType::JPEG, x))
+        .map_err(error_response)
+}
+
+// What `cat.identified` tells: the segment with its tags, when and where it was seen, and what
+// it was tagged before.
+async fn identified_payload(
+    pg_conn: &mut AsyncPgConnection,
+    segment_id: i32,
+    previous: Option<i32>,
+) -> QueryResult<Value> {
+    let segment: Vec<Segment> = segments::table
+        .find(segment_id)
+        .select(Segment::as_select())
+        .load(pg_conn)
+        .await?;
+    let segment = SegmentWithTag::with_tags(pg_conn, segment).await?.remove(0);
+    let (camera_id, captured_at): (Option<i32>, DateTime<Utc>) = images::table
+        .find(segment.image_id)
+        .select((images::camera_id, images::captured_at))
+        .first(pg_conn)
+        .await?;
+    Ok(json!({
+        "image_id": segment.image_id,
+        "camera_id": camera_id,
+        "captured_at": captured_at,
+        "previous_tag_id": previous,
+        "segment": segment,
+    }))
+}
+
+// Feeding sessions around the image are redone, as the cat may have changed. A segment getting
+// another cat queues `cat.identified`, once per change. Along with the segment comes the tag it
+// had before, for `labeled` to tell about the change.
+pub async fn apply_label(
+    pg_conn: &mut AsyncPgConnection,
+    id: i32,
+    label: SegmentLabel,
+) -> QueryResult<(Segment, Option<i32>)> {
+    let previous: Option<i32> = segments::table
+        .find(id)
+        .select(segments::tagged_as)
+        .for_update()
+        .first(pg_conn)
+        .await?;
+    let segment: Segment = diesel::update(segments::table.find(id))
+        .set(label)
+        .returning(Segment::as_returning())
+        .get_result(pg_conn)
+        .await?;
+    diesel::update(images::table.find(segment.image_id))
+        .set(images::sessioned.eq(false))
+        .execute(pg_conn)
+        .await?;
+    if segment.tagged_as.is_some() && segment.tagged_as != previous {
+        let payload = identified_payload(pg_conn, id, previous).await?;
+        webhooking::enqueue(pg_conn, CAT_IDENTIFIED, payload).await?;
+    }
+    Ok((segment, previous))
+}
+
+// Once the label is committed, if the cat changed. Failing to tell only gets logged, the
+// label stands.
+pub async fn labeled(
+    state: &StoreState,
+    pg_conn: &mut AsyncPgConnection,
+    segment: &Segment,
+    previous: Option<i32>,
+) {
+    if segment.tagged_as == previous {
+        return;
+    }
+    if segment.tagged_as.is_some() {
+        match identified_payload(pg_conn, segment.id, previous).await {
+            Ok(payload) => streaming::publish(&state.redis_pool, CAT_IDENTIFIED, payload).await,
+            Err(e) => error!(
+                "Telling dashboards about segment {} failed: {e:?}",
+                segment.id
+            ),
+        }
+    }
+    if let Some(publisher) = &state.publisher {
+        let cats: Vec<i32> = previous.into_iter().chain(segment.tagged_as).collect();
+        if let Err(e) = publisher.seen(pg_conn, &cats).await {
+            error!("Publishing where cats {cats:?} were seen failed: {e:?}");
+        }
+    }
+}
+
+#[instrument]
+#[put("/<id>", format = "application/json", data = "<label>")]
+pub async fn label_segment(
+    state: &State<StoreState>,
+    id: i32,
+    label: Json<SegmentLabel>,
+) -> Result<Json<SegmentWithTag>, (Status, String)> {
+    let result: anyhow::Result<SegmentWithTag> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        let (segment, previous) = pg_conn
+            .transaction(|pg_conn| {
+                (async move { apply_label(pg_conn, id, label.into_inner()).await }).scope_boxed()
+            })
+            .await?;
+        labeled(state, &mut pg_conn, &segment, previous).await;
+        let mut segments = SegmentWithTag::with_tags(&mut pg_conn, vec![segment]).await?;
+        segments.remove(0)
+    };
+    result.map(Json).map_err(error_response)
+}
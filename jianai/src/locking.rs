@@ -0,0 +1,32 @@
+// Postgres advisory locks, for loops every `store` process runs but only one should at a time.
+use diesel::{sql_function, sql_types::BigInt, QueryResult};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+// Lock keys, one per loop.
+pub const SESSIONING: i64 = 0x6a69_616e_6169_0001;
+pub const RETAINING: i64 = 0x6a69_616e_6169_0002;
+
+sql_function!(fn pg_try_advisory_xact_lock(key: BigInt) -> Bool);
+sql_function!(fn pg_try_advisory_lock(key: BigInt) -> Bool);
+sql_function!(fn pg_advisory_unlock(key: BigInt) -> Bool);
+
+// Held until the transaction ends. False if another one holds it.
+pub async fn try_xact_lock(pg_conn: &mut AsyncPgConnection, key: i64) -> QueryResult<bool> {
+    diesel::select(pg_try_advisory_xact_lock(key))
+        .get_result(pg_conn)
+        .await
+}
+
+// Held by the connection until `unlock`, for passes spanning many transactions.
+pub async fn try_lock(pg_conn: &mut AsyncPgConnection, key: i64) -> QueryResult<bool> {
+    diesel::select(pg_try_advisory_lock(key))
+        .get_result(pg_conn)
+        .await
+}
+
+pub async fn unlock(pg_conn: &mut AsyncPgConnection, key: i64) -> QueryResult<()> {
+    diesel::select(pg_advisory_unlock(key))
+        .get_result::<bool>(pg_conn)
+        .await?;
+    Ok(())
+}
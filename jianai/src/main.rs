@@ -1,11 +1,32 @@
 #![feature(try_blocks)]
+mod alerting;
+mod alerts;
+mod analytics;
 mod app_state;
+mod checking;
 mod cli;
+mod evaluating;
 mod handlers;
+mod images;
+mod ingest;
+mod labeling;
+mod locking;
+mod migrating;
 mod model;
+mod publishing;
+mod reporting;
+mod resumable;
+mod retaining;
+mod reviewing;
 mod schema;
 mod segmenting;
+mod sessioning;
+mod storage;
+mod streaming;
+mod sweeping;
 mod types;
+mod webhooking;
+mod webhooks;
 
 use anyhow::{anyhow, Result};
 use clap::Parser;
@@ -17,7 +38,7 @@ use diesel_async::{
 use handlers::*;
 use redis_pool::RedisPool;
 use rocket::routes;
-use std::sync::atomic::AtomicU64;
+use std::sync::Mutex;
 use tracing_subscriber::prelude::*;
 
 #[tokio::main]
@@ -31,15 +52,20 @@ async fn main() -> Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    if let Ok(cli::Standalone::ParseId { upload_id }) = cli::Standalone::try_parse() {
+        return print_id(&upload_id);
+    }
     let args = Params::parse();
 
     let redis_client = redis::Client::open(args.redis_address)?;
-    let redis_pool = RedisPool::from(redis_client);
+    let redis_pool = RedisPool::from(redis_client.clone());
 
     let pg_manager =
         AsyncDieselConnectionManager::<AsyncPgConnection>::new(args.pg_params.get_conn_str());
     let pg_pool = Pool::builder().build(pg_manager).await?;
 
+    let storage = storage::Storage::open(&args.storage)?;
+
     let mut web = rocket::build()
         .mount("/healthz", routes![healthz])
         .mount("/status", routes![status]);
@@ -47,32 +73,243 @@ async fn main() -> Result<()> {
     match args.cmd {
         cli::SubCmd::Store {
             upload_image_http_path,
+            meta_schema,
+            upload_session_ttl,
+            sweep_interval,
+            retention_rules,
+            retention_interval,
+            session_gap,
+            session_interval,
+            alert_interval,
+            webhook_interval,
+            partial_folder,
+            max_image_bytes,
+            max_image_dimension,
+            canonical_format,
+            thumbnail_sizes,
+            label_font,
+            mqtt_params,
         } => {
+            // Annotated images can do without labels.
+            let label_font = match tokio::fs::read(&label_font).await {
+                Ok(font) => Some(ab_glyph::FontArc::try_from_vec(font)?),
+                Err(e) => {
+                    tracing::warn!("No label font {label_font:?}: {e}");
+                    None
+                }
+            };
+            let canonical_format = canonical_format
+                .map(|ext| {
+                    image::ImageFormat::from_extension(&ext)
+                        .ok_or(anyhow!("Unknown image format {ext}"))
+                })
+                .transpose()?;
+            let meta_schema: serde_json::Value = match meta_schema {
+                Some(path) => serde_json::from_str(&tokio::fs::read_to_string(path).await?)?,
+                None => serde_json::from_str(include_str!("../meta_schema.json"))?,
+            };
+            tokio::spawn(sweeping::sweeping_loop(
+                redis_pool.clone(),
+                pg_pool.clone(),
+                storage.clone(),
+                partial_folder.clone(),
+                upload_session_ttl,
+                sweep_interval,
+            ));
+            tokio::spawn(sessioning::sessioning_loop(
+                pg_pool.clone(),
+                session_gap,
+                session_interval,
+            ));
+            tokio::spawn(alerting::alerting_loop(pg_pool.clone(), alert_interval));
+            tokio::spawn(webhooking::webhooking_loop(
+                pg_pool.clone(),
+                webhook_interval,
+            ));
+            let (events, _) = tokio::sync::broadcast::channel(1024);
+            tokio::spawn(streaming::relaying_loop(redis_client, events.clone()));
+            if let Some(path) = retention_rules {
+                tokio::spawn(retaining::retaining_loop(
+                    pg_pool.clone(),
+                    storage.clone(),
+                    retaining::load_rules(&path).await?,
+                    retention_interval,
+                ));
+            }
             let state = app_state::StoreState {
                 redis_pool,
                 pg_pool,
-                id_prefix: format!(
-                    "{}{}",
-                    gethostname::gethostname()
-                        .into_string()
-                        .map_err(|e| { anyhow!("{e:?}") })?,
-                    std::process::id()
-                ),
-                id_counter: AtomicU64::new(0),
+                id_generator: Mutex::new(ulid::Generator::new()),
                 upload_image_http_path: upload_image_http_path,
-                image_folder: args.image_folder,
+                storage,
+                partial_folder,
+                upload_session_ttl,
+                image_limits: ingest::ImageLimits {
+                    max_bytes: max_image_bytes,
+                    max_dimension: max_image_dimension,
+                    canonical_format,
+                },
+                thumbnail_sizes,
+                label_font,
+                meta_schema: jsonschema::validator_for(&meta_schema)
+                    .map_err(|e| anyhow!("Invalid metadata schema: {e}"))?,
+                events,
+                publisher: publishing::Publisher::connect(mqtt_params)?,
             };
+            // Rocket turns away bodies past these before handlers read them: an image of
+            // `upload_image`, an image field or the whole form of `upload`.
+            let figment = web
+                .figment()
+                .clone()
+                .merge(("limits.file", max_image_bytes))
+                .merge((
+                    "limits.data-form",
+                    max_image_bytes.saturating_mul(MAX_UPLOAD_IMAGES),
+                ));
             web = web
+                .configure(figment)
                 .mount("/upload_meta", routes![upload_meta])
                 .mount("/upload_image", routes![upload_image])
+                .mount("/upload", routes![upload])
+                .mount("/uploads", routes![upload_status])
+                .mount(
+                    "/images",
+                    routes![
+                        images::get_thumbnail,
+                        images::get_annotated,
+                        images::delete_image,
+                        images::delete_matching
+                    ],
+                )
+                .mount("/sessions", routes![analytics::list_sessions])
+                .mount(
+                    "/stats",
+                    routes![analytics::all_cat_stats, analytics::get_cat_stats],
+                )
+                .mount(
+                    "/alerts",
+                    routes![
+                        alerts::list_rules,
+                        alerts::create_rule,
+                        alerts::update_rule,
+                        alerts::delete_rule,
+                        alerts::list_alerts
+                    ],
+                )
+                .mount(
+                    "/webhooks",
+                    routes![
+                        webhooks::list_webhooks,
+                        webhooks::create_webhook,
+                        webhooks::update_webhook,
+                        webhooks::delete_webhook,
+                        webhooks::ping_webhook,
+                        webhooks::list_deliveries,
+                        webhooks::retry_delivery
+                    ],
+                )
+                .mount("/events", routes![streaming::stream_events])
+                .mount("/ui", routes![labeling::label_page])
+                .mount("/report", routes![reporting::get_report])
+                .mount(
+                    "/review",
+                    routes![
+                        reviewing::next_review,
+                        reviewing::answer_review,
+                        reviewing::throughput
+                    ],
+                )
+                .mount("/tags", routes![labeling::list_tags, labeling::create_tag])
+                .mount(
+                    "/segments",
+                    routes![
+                        labeling::list_segments,
+                        labeling::get_crop,
+                        labeling::label_segment
+                    ],
+                )
+                .mount(
+                    "/resumable",
+                    routes![resumable::create, resumable::offset, resumable::append],
+                )
+                .mount(
+                    "/cameras",
+                    routes![
+                        list_cameras,
+                        get_camera,
+                        create_camera,
+                        update_camera,
+                        delete_camera
+                    ],
+                )
                 .manage(state);
             web.launch().await?;
         }
-        cli::SubCmd::Segment { model_path } => {
+        cli::SubCmd::Segment {
+            model_path,
+            threshold,
+            mqtt_params,
+        } => {
+            let publisher = publishing::Publisher::connect(mqtt_params)?;
             tokio::spawn(web.launch());
-            segmenting::segmenting_loop(redis_pool, pg_pool, args.image_folder, model_path).await?;
+            segmenting::segmenting_loop(
+                redis_pool, pg_pool, storage, model_path, threshold, publisher,
+            )
+            .await?;
+        }
+        cli::SubCmd::Migrate => {
+            migrating::migrate_layout(pg_pool, storage).await?;
+        }
+        cli::SubCmd::Retain { rules, dry_run } => {
+            let rules = retaining::load_rules(&rules).await?;
+            retaining::retain(&pg_pool, &storage, &rules, dry_run).await?;
+        }
+        cli::SubCmd::Fsck { repair, min_age } => {
+            checking::fsck(pg_pool, storage, repair, min_age).await?;
+        }
+        cli::SubCmd::Evaluate {
+            model_path,
+            ground_truth,
+            threshold,
+            output,
+        } => {
+            let ground_truth = evaluating::load_ground_truth(&ground_truth).await?;
+            let report =
+                evaluating::evaluate(pg_pool, storage, model_path, ground_truth, threshold).await?;
+            let report = serde_json::to_string_pretty(&report)?;
+            match output {
+                Some(path) => tokio::fs::write(path, report).await?,
+                None => println!("{report}"),
+            }
+        }
+        cli::SubCmd::Report {
+            from,
+            to,
+            camera_id,
+            period,
+            format,
+            table,
+        } => {
+            let from = from.as_deref().map(parse_time).transpose()?;
+            let to = to.as_deref().map(parse_time).transpose()?;
+            let mut pg_conn = pg_pool.get().await?;
+            let report = reporting::accuracy(&mut pg_conn, from, to, camera_id, period).await?;
+            match format {
+                reporting::ReportFormat::Json => {
+                    println!("{}", serde_json::to_string_pretty(&report)?)
+                }
+                reporting::ReportFormat::Csv => {
+                    print!("{}", reporting::to_csv(&report, table))
+                }
+            }
         }
     }
 
     Ok(())
 }
+
+fn print_id(upload_id: &str) -> Result<()> {
+    let (created_at, random) = app_state::parse_id(upload_id)?;
+    println!("{upload_id}: made at {created_at}, random part {random:020x}");
+    Ok(())
+}
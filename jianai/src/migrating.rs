@@ -0,0 +1,65 @@
+// Moves images stored under their upload id, right in the storage root, to content-addressed names.
+use crate::images::THUMBNAIL_FOLDER;
+use crate::ingest::{content_name, digest};
+use crate::model::Image;
+use crate::schema::*;
+use crate::storage::Storage;
+use anyhow::Result;
+use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl};
+use tracing::{info, warn};
+
+pub async fn migrate_layout(pg_pool: Pool<AsyncPgConnection>, storage: Storage) -> Result<()> {
+    let mut pg_conn = pg_pool.get().await?;
+    let images: Vec<Image> = images::table
+        .select(Image::as_select())
+        .order(images::id)
+        .load(&mut pg_conn)
+        .await?;
+    let thumbnails: Vec<String> = storage
+        .list(Some(THUMBNAIL_FOLDER))
+        .await?
+        .into_iter()
+        .map(|x| x.name)
+        .collect();
+
+    let (mut moved, mut skipped) = (0, 0);
+    // Content-addressed names are always in folders, the old ones never.
+    for image in images.into_iter().filter(|x| !x.filename.contains('/')) {
+        let Some(content) = storage.try_get(&image.filename).await? else {
+            warn!("Image {} has no file {}", image.id, image.filename);
+            skipped += 1;
+            continue;
+        };
+        if digest(&content) != image.digest {
+            warn!("Image {} does not match its digest", image.id);
+            skipped += 1;
+            continue;
+        }
+        let format = match image::guess_format(&content) {
+            Ok(format) => format,
+            Err(e) => {
+                warn!("Image {} is not an image we can read: {e}", image.id);
+                skipped += 1;
+                continue;
+            }
+        };
+        let name = content_name(&image.digest, format);
+        storage.put(&name, content).await?;
+        diesel::update(images::table.find(image.id))
+            .set(images::filename.eq(&name))
+            .execute(&mut pg_conn)
+            .await?;
+        // Should we stop here, the old file is left for `fsck` to report as an orphan.
+        storage.delete(&image.filename).await?;
+        // Made again on request.
+        let suffix = format!("/{}.jpg", image.filename);
+        for thumbnail in thumbnails.iter().filter(|x| x.ends_with(&suffix)) {
+            storage.delete(thumbnail).await?;
+        }
+        info!("Moved image {} to {name}", image.id);
+        moved += 1;
+    }
+    info!("Moved {moved} images, {skipped} left as they were");
+    Ok(())
+}
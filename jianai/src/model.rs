@@ -1,7 +1,10 @@
 use diesel::prelude::*;
 use serde_json::Value;
 use crate::types::*;
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Queryable, Selectable, Serialize)]
 #[diesel(table_name = crate::schema::images)]
@@ -12,6 +15,64 @@ pub struct Image {
     pub digest: Vec<u8>,
     pub metadata: Option<Value>,
     pub segmented: bool,
+    pub camera_id: Option<i32>,
+    pub captured_at: DateTime<Utc>,
+    pub upload_id: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub format: Option<String>,
+    pub expired_at: Option<DateTime<Utc>>,
+    pub sessioned: bool,
+    pub uploaded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::images)]
+pub struct NewImage {
+    pub filename: String,
+    pub digest: Vec<u8>,
+    pub metadata: Option<Value>,
+    pub camera_id: Option<i32>,
+    pub captured_at: DateTime<Utc>,
+    pub upload_id: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::deletions)]
+pub struct NewDeletion {
+    pub image_id: i32,
+    pub filename: String,
+    pub digest: Vec<u8>,
+    pub camera_id: Option<i32>,
+    pub captured_at: DateTime<Utc>,
+    pub deleted_by: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::feeding_sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct FeedingSession {
+    pub id: i32,
+    pub camera_id: i32,
+    pub tag_id: i32,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub duration_seconds: i32,
+    pub frame_count: i32,
+}
+
+#[derive(Debug, Clone, Insertable)]
+#[diesel(table_name = crate::schema::feeding_sessions)]
+pub struct NewFeedingSession {
+    pub camera_id: i32,
+    pub tag_id: i32,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub frame_count: i32,
 }
 
 #[derive(Debug, Queryable, Selectable, Serialize)]
@@ -24,9 +85,10 @@ pub struct Segment {
     pub identified_as: Option<i32>,
     pub tagged_as: Option<i32>,
     pub low_quality: bool,
+    pub confidence: Option<f32>,
 }
 
-#[derive(Debug, Queryable, Selectable, Serialize)]
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
 #[diesel(table_name = crate::schema::tags)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 pub struct Tag {
@@ -34,7 +96,22 @@ pub struct Tag {
     pub tag: String,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Insertable, Deserialize)]
+#[diesel(table_name = crate::schema::tags)]
+pub struct NewTag {
+    pub tag: String,
+}
+
+// What a human says about a segment.
+#[derive(Debug, AsChangeset, Deserialize)]
+#[diesel(table_name = crate::schema::segments)]
+#[diesel(treat_none_as_null = true)]
+pub struct SegmentLabel {
+    pub tagged_as: Option<i32>,
+    pub low_quality: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct SegmentWithTag {
     pub id: i32,
     pub image_id: i32,
@@ -42,4 +119,204 @@ pub struct SegmentWithTag {
     pub identified_as: Option<Tag>,
     pub tagged_as: Option<Tag>,
     pub low_quality: bool,
+    pub confidence: Option<f32>,
+}
+impl SegmentWithTag {
+    pub async fn for_images(
+        pg_conn: &mut AsyncPgConnection,
+        image_ids: &[i32],
+    ) -> QueryResult<Vec<SegmentWithTag>> {
+        use crate::schema::*;
+        let segments: Vec<Segment> = segments::table
+            .filter(segments::image_id.eq_any(image_ids))
+            .select(Segment::as_select())
+            .order(segments::id)
+            .load(pg_conn)
+            .await?;
+        SegmentWithTag::with_tags(pg_conn, segments).await
+    }
+
+    pub async fn with_tags(
+        pg_conn: &mut AsyncPgConnection,
+        segments: Vec<Segment>,
+    ) -> QueryResult<Vec<SegmentWithTag>> {
+        use crate::schema::*;
+        let tag_ids: Vec<i32> = segments
+            .iter()
+            .flat_map(|x| [x.identified_as, x.tagged_as])
+            .flatten()
+            .collect();
+        let tags: HashMap<i32, Tag> = tags::table
+            .filter(tags::id.eq_any(tag_ids))
+            .select(Tag::as_select())
+            .load(pg_conn)
+            .await?
+            .into_iter()
+            .map(|x| (x.id, x))
+            .collect();
+        Ok(segments
+            .into_iter()
+            .map(|x| SegmentWithTag {
+                id: x.id,
+                image_id: x.image_id,
+                bounding_box: x.bounding_box,
+                identified_as: x.identified_as.and_then(|id| tags.get(&id).cloned()),
+                tagged_as: x.tagged_as.and_then(|id| tags.get(&id).cloned()),
+                low_quality: x.low_quality,
+                confidence: x.confidence,
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::cameras)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Camera {
+    pub id: i32,
+    pub name: String,
+    pub location: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub zones: Option<Value>,
+    pub timezone: String,
+}
+
+#[derive(Debug, Insertable, AsChangeset, Deserialize)]
+#[diesel(table_name = crate::schema::cameras)]
+#[diesel(treat_none_as_null = true)]
+pub struct NewCamera {
+    pub name: String,
+    pub location: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub zones: Option<Value>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::alert_rules)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AlertRule {
+    pub id: i32,
+    pub name: String,
+    pub condition: Value,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Insertable, AsChangeset, Deserialize)]
+#[diesel(table_name = crate::schema::alert_rules)]
+pub struct NewAlertRule {
+    pub name: String,
+    // A `Condition`.
+    pub condition: Value,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::alerts)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Alert {
+    pub id: i32,
+    pub rule_id: i32,
+    pub message: String,
+    pub fired_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::alerts)]
+pub struct NewAlert {
+    pub rule_id: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::webhooks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<String>,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Insertable, AsChangeset, Deserialize)]
+#[diesel(table_name = crate::schema::webhooks)]
+pub struct NewWebhook {
+    pub url: String,
+    // Key of the HMAC-SHA256 in `X-Jianai-Signature`.
+    pub secret: String,
+    pub events: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: Value,
+    pub created_at: DateTime<Utc>,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+    pub failed_at: Option<DateTime<Utc>>,
+    pub status_code: Option<i32>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::webhook_deliveries)]
+pub struct NewWebhookDelivery {
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: Value,
+}
+
+#[derive(Debug, Clone, Queryable, Selectable, Serialize)]
+#[diesel(table_name = crate::schema::reviews)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Review {
+    pub id: i32,
+    pub segment_id: i32,
+    pub reviewer: String,
+    pub priority: f32,
+    pub leased_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub answered_at: Option<DateTime<Utc>>,
+    pub tagged_as: Option<i32>,
+    pub low_quality: Option<bool>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::schema::reviews)]
+pub struct NewReview {
+    pub segment_id: i32,
+    pub reviewer: String,
+    pub priority: f32,
+    pub expires_at: DateTime<Utc>,
+}
+
+// A segment to review, and why it was picked.
+#[derive(Debug, Serialize)]
+pub struct Lease {
+    pub review: Review,
+    pub reasons: Vec<&'static str>,
+    pub segment: SegmentWithTag,
 }
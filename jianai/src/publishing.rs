@@ -0,0 +1,286 @@
+// Publishes what the segmenting worker finds to an MQTT broker. Per camera, the cat count and
+// the cats in each zone are retained, as is where each cat was last seen, which the store
+// updates as segments are tagged. Home Assistant discovery makes each cat a sensor of when it
+// was last seen, and each camera one of its count.
+use crate::cli::MqttParams;
+use crate::model::{Camera, Image, SegmentWithTag};
+use crate::schema::*;
+use crate::types::Point;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use diesel::{
+    BoolExpressionMethods, ExpressionMethods, NullableExpressionMethods, OptionalExtension,
+    QueryDsl, SelectableHelper,
+};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{error, warn};
+
+// Image id, camera, capture time and camera name of where a cat was last seen.
+type Sighting = (i32, Option<i32>, DateTime<Utc>, Option<String>);
+
+#[derive(Clone)]
+pub struct Publisher {
+    client: AsyncClient,
+    topic_prefix: String,
+    discovery_prefix: String,
+    // Discovery messages are retained, so once per run is enough.
+    announced: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Publisher {
+    // None without a broker. Reconnects by itself when the broker goes away.
+    pub fn connect(params: MqttParams) -> Result<Option<Publisher>> {
+        let Some(broker) = params.mqtt_broker else {
+            return Ok(None);
+        };
+        let (host, port) = broker
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("Expected host:port for the MQTT broker, got {broker}"))?;
+        let hostname = gethostname::gethostname()
+            .into_string()
+            .map_err(|e| anyhow!("{e:?}"))?;
+        // The broker drops a client when another connects with its id, and a store and workers
+        // may share a host.
+        let client_id = format!("jianai-{hostname}-{}", std::process::id());
+        let mut options = MqttOptions::new(client_id, host, port.parse()?);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (params.mqtt_username, params.mqtt_password) {
+            options.set_credentials(username, password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, 100);
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = eventloop.poll().await {
+                    error!("MQTT connection failed: {e:?}");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+        Ok(Some(Publisher {
+            client,
+            topic_prefix: params.mqtt_topic_prefix,
+            discovery_prefix: params.mqtt_discovery_prefix,
+            announced: Arc::new(Mutex::new(HashSet::new())),
+        }))
+    }
+
+    async fn publish(&self, topic: &str, retain: bool, payload: &Value) -> Result<()> {
+        self.client
+            .publish(
+                topic,
+                QoS::AtLeastOnce,
+                retain,
+                serde_json::to_vec(payload)?,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn announce(&self, component: &str, unique_id: String, config: Value) -> Result<()> {
+        if !self.announced.lock().unwrap().insert(unique_id.clone()) {
+            return Ok(());
+        }
+        let mut config = config;
+        config["unique_id"] = json!(unique_id);
+        config["device"] = json!({ "identifiers": ["jianai"], "name": "Jianai" });
+        let topic = format!("{}/{component}/{unique_id}/config", self.discovery_prefix);
+        self.publish(&topic, true, &config).await
+    }
+
+    pub async fn segmented(
+        &self,
+        pg_conn: &mut AsyncPgConnection,
+        image: &Image,
+        segments: &[SegmentWithTag],
+    ) -> Result<()> {
+        let prefix = &self.topic_prefix;
+        let camera: Option<Camera> = match image.camera_id {
+            Some(camera_id) => cameras::table
+                .find(camera_id)
+                .select(Camera::as_select())
+                .first(pg_conn)
+                .await
+                .optional()?,
+            None => None,
+        };
+        let zones = camera.as_ref().map(zones).unwrap_or_default();
+        let occupancy: HashMap<&str, usize> = zones
+            .iter()
+            .map(|(name, polygon)| {
+                let count = segments
+                    .iter()
+                    .filter(|x| inside(x.bounding_box.center(), polygon))
+                    .count();
+                (name.as_str(), count)
+            })
+            .collect();
+        // The tag of each segment known to be a cat, by a human or the identifier.
+        let cats: Vec<_> = segments
+            .iter()
+            .filter_map(|x| x.tagged_as.as_ref().or(x.identified_as.as_ref()))
+            .collect();
+        self.publish(
+            &format!("{prefix}/images/segmented"),
+            false,
+            &json!({
+                "image_id": image.id,
+                "camera_id": image.camera_id,
+                "captured_at": image.captured_at,
+                "cat_count": segments.len(),
+                "cats": cats,
+                "zones": occupancy,
+            }),
+        )
+        .await?;
+
+        if let Some(camera) = &camera {
+            let topic = format!("{prefix}/cameras/{}", camera.id);
+            self.announce(
+                "sensor",
+                format!("jianai_camera_{}_cats", camera.id),
+                json!({
+                    "name": format!("Cats at {}", camera.name),
+                    "state_topic": format!("{topic}/cat_count"),
+                    "state_class": "measurement",
+                }),
+            )
+            .await?;
+            self.publish(&format!("{topic}/cat_count"), true, &json!(segments.len()))
+                .await?;
+            for (zone, count) in &occupancy {
+                self.publish(&format!("{topic}/zones/{zone}"), true, &json!(count))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Where the cats were last seen, for after their segments were tagged. Looked up rather
+    // than taken from the segment, as tagging an old image does not make it the latest.
+    pub async fn seen(&self, pg_conn: &mut AsyncPgConnection, tag_ids: &[i32]) -> Result<()> {
+        for tag_id in tag_ids {
+            let Some(tag): Option<String> = tags::table
+                .find(tag_id)
+                .select(tags::tag)
+                .first(pg_conn)
+                .await
+                .optional()?
+            else {
+                continue;
+            };
+            // A human tag wins over what the identifier thinks.
+            let last: Option<Sighting> = segments::table
+                .inner_join(images::table.left_join(cameras::table))
+                .filter(
+                    segments::tagged_as.eq(tag_id).or(segments::tagged_as
+                        .is_null()
+                        .and(segments::identified_as.eq(tag_id))),
+                )
+                .select((
+                    images::id,
+                    images::camera_id,
+                    images::captured_at,
+                    cameras::name.nullable(),
+                ))
+                .order(images::captured_at.desc())
+                .first(pg_conn)
+                .await
+                .optional()?;
+            // Untagged from its only image, the cat keeps what was published before.
+            let Some((image_id, camera_id, captured_at, camera)) = last else {
+                continue;
+            };
+            let topic = format!("{}/cats/{tag_id}/last_seen", self.topic_prefix);
+            self.announce(
+                "sensor",
+                format!("jianai_cat_{tag_id}"),
+                json!({
+                    "name": tag,
+                    "state_topic": topic,
+                    "value_template": "{{ value_json.captured_at }}",
+                    "device_class": "timestamp",
+                    "json_attributes_topic": topic,
+                }),
+            )
+            .await?;
+            self.publish(
+                &topic,
+                true,
+                &json!({
+                    "captured_at": captured_at,
+                    "image_id": image_id,
+                    "camera_id": camera_id,
+                    "camera": camera,
+                }),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+fn zones(camera: &Camera) -> HashMap<String, Vec<[f32; 2]>> {
+    let Some(zones) = &camera.zones else {
+        return HashMap::new();
+    };
+    serde_json::from_value(zones.clone()).unwrap_or_else(|e| {
+        warn!("Ignoring zones of camera {}: {e}", camera.name);
+        HashMap::new()
+    })
+}
+
+// Even-odd rule.
+fn inside(point: Point, polygon: &[[f32; 2]]) -> bool {
+    let mut inside = false;
+    for (i, [x1, y1]) in polygon.iter().enumerate() {
+        let [x2, y2] = polygon[(i + 1) % polygon.len()];
+        if (*y1 > point.y) != (y2 > point.y)
+            && point.x < x1 + (point.y - y1) * (x2 - x1) / (y2 - y1)
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inside_by_even_odd_rule() {
+        let square = [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]];
+        assert!(inside(Point { x: 5.0, y: 5.0 }, &square));
+        assert!(!inside(Point { x: 15.0, y: 5.0 }, &square));
+        assert!(!inside(Point { x: 5.0, y: -1.0 }, &square));
+        // A U shape, the notch between its arms is outside.
+        let u = [
+            [0.0, 0.0],
+            [9.0, 0.0],
+            [9.0, 9.0],
+            [6.0, 9.0],
+            [6.0, 3.0],
+            [3.0, 3.0],
+            [3.0, 9.0],
+            [0.0, 9.0],
+        ];
+        assert!(inside(Point { x: 1.5, y: 6.0 }, &u));
+        assert!(inside(Point { x: 7.5, y: 6.0 }, &u));
+        assert!(!inside(Point { x: 4.5, y: 6.0 }, &u));
+        assert!(inside(Point { x: 4.5, y: 1.5 }, &u));
+    }
+
+    #[test]
+    fn nothing_is_inside_a_degenerate_zone() {
+        assert!(!inside(Point { x: 0.0, y: 0.0 }, &[]));
+        assert!(!inside(Point { x: 1.0, y: 1.0 }, &[[1.0, 1.0]]));
+    }
+}
@@ -0,0 +1,374 @@
+// Measures the identifier against human corrections, to tell when it needs retraining.
+// Only segments with a human tag count, and low quality ones are left out. How many of them
+// were identified at all is reported apart from how many were identified right.
+use crate::analytics::Period;
+use crate::app_state::StoreState;
+use crate::handlers::*;
+use crate::schema::*;
+use crate::types::{AccuracyReport, ConfusionCell, GroupAccuracy, TagAccuracy};
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::{ExpressionMethods, NullableExpressionMethods, QueryDsl};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use rocket::{http::Status, serde::json::Json, *};
+use std::collections::{BTreeMap, HashMap};
+use tracing::instrument;
+
+#[derive(Debug, Clone, Copy, FromFormField, clap::ValueEnum)]
+pub enum ReportFormat {
+    Json,
+    // One of the tables, see `Table`.
+    Csv,
+}
+
+// The parts of an `AccuracyReport`, each its own CSV.
+#[derive(Debug, Clone, Copy, FromFormField, clap::ValueEnum)]
+pub enum Table {
+    Overall,
+    Tags,
+    Confusion,
+    Periods,
+    Models,
+}
+
+#[derive(Responder)]
+pub enum Report {
+    Json(Json<AccuracyReport>),
+    #[response(content_type = "text/csv")]
+    Csv(String),
+}
+impl Report {
+    pub fn new(report: AccuracyReport, format: ReportFormat, table: Table) -> Report {
+        match format {
+            ReportFormat::Json => Report::Json(Json(report)),
+            ReportFormat::Csv => Report::Csv(to_csv(&report, table)),
+        }
+    }
+}
+
+// `tagged_as`, `identified_as`, `identified_by` and when the image was captured.
+type Labeled = (i32, Option<i32>, Option<String>, DateTime<Utc>);
+
+fn ratio(part: i64, whole: i64) -> Option<f64> {
+    (whole > 0).then(|| part as f64 / whole as f64)
+}
+
+// `from` inclusive and `to` exclusive, on when the image was captured.
+pub async fn accuracy(
+    pg_conn: &mut AsyncPgConnection,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    camera_id: Option<i32>,
+    period: Period,
+) -> anyhow::Result<AccuracyReport> {
+    let mut query = segments::table
+        .inner_join(images::table)
+        .filter(segments::tagged_as.is_not_null())
+        .filter(segments::low_quality.eq(false))
+        .select((
+            segments::tagged_as.assume_not_null(),
+            segments::identified_as,
+            segments::identified_by,
+            images::captured_at,
+        ))
+        .into_boxed();
+    if let Some(from) = from {
+        query = query.filter(images::captured_at.ge(from));
+    }
+    if let Some(to) = to {
+        query = query.filter(images::captured_at.lt(to));
+    }
+    if let Some(camera_id) = camera_id {
+        query = query.filter(images::camera_id.eq(camera_id));
+    }
+    let rows: Vec<Labeled> = query.load(pg_conn).await?;
+    let names: HashMap<i32, String> = tags::table
+        .select((tags::id, tags::tag))
+        .load(pg_conn)
+        .await?
+        .into_iter()
+        .collect();
+    let name = |id: i32| {
+        names
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| format!("Cat {id}"))
+    };
+
+    let mut tagged: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut covered: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut identified: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut correct: BTreeMap<i32, i64> = BTreeMap::new();
+    let mut confusion: BTreeMap<(i32, Option<i32>), i64> = BTreeMap::new();
+    // Total, identified and correct.
+    let mut periods: BTreeMap<NaiveDate, (i64, i64, i64)> = BTreeMap::new();
+    let mut models: BTreeMap<Option<String>, (i64, i64, i64)> = BTreeMap::new();
+    for (tagged_as, identified_as, identified_by, captured_at) in &rows {
+        *tagged.entry(*tagged_as).or_default() += 1;
+        *confusion.entry((*tagged_as, *identified_as)).or_default() += 1;
+        let period = periods
+            .entry(period.start(captured_at.date_naive()))
+            .or_default();
+        period.0 += 1;
+        // Unidentified segments have no model to blame, nor are they wrong.
+        let Some(identified_as) = identified_as else {
+            continue;
+        };
+        let right = i64::from(tagged_as == identified_as);
+        *covered.entry(*tagged_as).or_default() += 1;
+        *identified.entry(*identified_as).or_default() += 1;
+        *correct.entry(*tagged_as).or_default() += right;
+        period.1 += 1;
+        period.2 += right;
+        let model = models.entry(identified_by.clone()).or_default();
+        model.0 += 1;
+        model.1 += 1;
+        model.2 += right;
+    }
+
+    let total = rows.len() as i64;
+    let all_identified = identified.values().sum();
+    let all_correct = correct.values().sum();
+    let mut tag_ids: Vec<i32> = tagged.keys().chain(identified.keys()).copied().collect();
+    tag_ids.sort();
+    tag_ids.dedup();
+    let group = |start, model, (total, identified, correct): (i64, i64, i64)| GroupAccuracy {
+        start,
+        model,
+        total,
+        identified,
+        correct,
+        accuracy: ratio(correct, identified),
+    };
+    Ok(AccuracyReport {
+        total,
+        identified: all_identified,
+        coverage: ratio(all_identified, total),
+        correct: all_correct,
+        accuracy: ratio(all_correct, all_identified),
+        tags: tag_ids
+            .into_iter()
+            .map(|id| {
+                let tagged = tagged.get(&id).copied().unwrap_or(0);
+                let covered = covered.get(&id).copied().unwrap_or(0);
+                let identified = identified.get(&id).copied().unwrap_or(0);
+                let correct = correct.get(&id).copied().unwrap_or(0);
+                TagAccuracy {
+                    tag_id: id,
+                    tag: name(id),
+                    tagged,
+                    covered,
+                    identified,
+                    correct,
+                    precision: ratio(correct, identified),
+                    recall: ratio(correct, covered),
+                }
+            })
+            .collect(),
+        confusion: confusion
+            .into_iter()
+            .map(|((tagged_as, identified_as), count)| ConfusionCell {
+                tagged_as: name(tagged_as),
+                identified_as: identified_as.map(name),
+                count,
+            })
+            .collect(),
+        periods: periods
+            .into_iter()
+            .map(|(start, counts)| group(Some(start), None, counts))
+            .collect(),
+        models: models
+            .into_iter()
+            .map(|(model, counts)| group(None, model, counts))
+            .collect(),
+    })
+}
+
+fn field(value: impl ToString) -> String {
+    let value = value.to_string();
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn optional(value: Option<impl ToString>) -> String {
+    value.map(field).unwrap_or_default()
+}
+
+// A header line, then a line per row.
+pub fn to_csv(report: &AccuracyReport, table: Table) -> String {
+    let mut lines = Vec::new();
+    match table {
+        Table::Overall => {
+            lines.push("total,identified,coverage,correct,accuracy".to_string());
+            lines.push(format!(
+                "{},{},{},{},{}",
+                report.total,
+                report.identified,
+                optional(report.coverage),
+                report.correct,
+                optional(report.accuracy)
+            ));
+        }
+        Table::Tags => {
+            lines.push("tag_id,tag,tagged,covered,identified,correct,precision,recall".to_string());
+            for x in &report.tags {
+                lines.push(format!(
+                    "{},{},{},{},{},{},{},{}",
+                    x.tag_id,
+                    field(&x.tag),
+                    x.tagged,
+                    x.covered,
+                    x.identified,
+                    x.correct,
+                    optional(x.precision),
+                    optional(x.recall)
+                ));
+            }
+        }
+        Table::Confusion => {
+            lines.push("tagged_as,identified_as,count".to_string());
+            for x in &report.confusion {
+                lines.push(format!(
+                    "{},{},{}",
+                    field(&x.tagged_as),
+                    optional(x.identified_as.as_ref()),
+                    x.count
+                ));
+            }
+        }
+        Table::Periods | Table::Models => {
+            let (key, groups) = match table {
+                Table::Periods => ("start", &report.periods),
+                _ => ("model", &report.models),
+            };
+            lines.push(format!("{key},total,identified,correct,accuracy"));
+            for x in groups {
+                let key = match table {
+                    Table::Periods => optional(x.start),
+                    _ => optional(x.model.as_ref()),
+                };
+                lines.push(format!(
+                    "{},{},{},{},{}",
+                    key,
+                    x.total,
+                    x.identified,
+                    x.correct,
+                    optional(x.accuracy)
+                ));
+            }
+        }
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+// Times are RFC 3339. JSON unless `format` says otherwise, the overall table if CSV and no
+// `table` is given.
+#[instrument]
+#[get("/?<from>&<to>&<camera_id>&<period>&<format>&<table>")]
+pub async fn get_report(
+    state: &State<StoreState>,
+    from: Option<String>,
+    to: Option<String>,
+    camera_id: Option<i32>,
+    period: Option<Period>,
+    format: Option<ReportFormat>,
+    table: Option<Table>,
+) -> Result<Report, (Status, String)> {
+    let result: anyhow::Result<AccuracyReport> = try {
+        let from = from.as_deref().map(parse_time).transpose()?;
+        let to = to.as_deref().map(parse_time).transpose()?;
+        let mut pg_conn = state.pg_pool.get().await?;
+        let period = period.unwrap_or(Period::Day);
+        accuracy(&mut pg_conn, from, to, camera_id, period).await?
+    };
+    result
+        .map(|x| {
+            Report::new(
+                x,
+                format.unwrap_or(ReportFormat::Json),
+                table.unwrap_or(Table::Overall),
+            )
+        })
+        .map_err(error_response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> AccuracyReport {
+        AccuracyReport {
+            total: 3,
+            identified: 2,
+            coverage: Some(2.0 / 3.0),
+            correct: 1,
+            accuracy: Some(0.5),
+            tags: vec![TagAccuracy {
+                tag_id: 1,
+                tag: "Mochi, \"the\" grey".to_string(),
+                tagged: 3,
+                covered: 2,
+                identified: 1,
+                correct: 1,
+                precision: Some(1.0),
+                recall: Some(0.5),
+            }],
+            confusion: vec![
+                ConfusionCell {
+                    tagged_as: "Mochi".to_string(),
+                    identified_as: Some("Mochi".to_string()),
+                    count: 1,
+                },
+                ConfusionCell {
+                    tagged_as: "Mochi".to_string(),
+                    identified_as: None,
+                    count: 1,
+                },
+            ],
+            periods: Vec::new(),
+            models: vec![GroupAccuracy {
+                start: None,
+                model: None,
+                total: 2,
+                identified: 2,
+                correct: 1,
+                accuracy: Some(0.5),
+            }],
+        }
+    }
+
+    #[test]
+    fn quotes_fields_with_separators() {
+        assert_eq!(field("Mochi"), "Mochi");
+        assert_eq!(field("a,b"), "\"a,b\"");
+        assert_eq!(field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn one_table_per_csv() {
+        assert_eq!(
+            to_csv(&report(), Table::Overall),
+            "total,identified,coverage,correct,accuracy\n3,2,0.6666666666666666,1,0.5\n"
+        );
+        assert_eq!(
+            to_csv(&report(), Table::Tags),
+            "tag_id,tag,tagged,covered,identified,correct,precision,recall\n\
+             1,\"Mochi, \"\"the\"\" grey\",3,2,1,1,1,0.5\n"
+        );
+        assert_eq!(
+            to_csv(&report(), Table::Confusion),
+            "tagged_as,identified_as,count\nMochi,Mochi,1\nMochi,,1\n"
+        );
+        assert_eq!(
+            to_csv(&report(), Table::Periods),
+            "start,total,identified,correct,accuracy\n"
+        );
+        assert_eq!(
+            to_csv(&report(), Table::Models),
+            "model,total,identified,correct,accuracy\n,2,2,1,0.5\n"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// tus-like resumable upload of the image of an upload session created by `upload_meta`.
+// POST creates it with the total `Upload-Length` and optionally the hex SHA3-224 `Upload-Digest`,
+// PATCH appends a chunk at `Upload-Offset`, HEAD tells the offset to resume from.
+// Chunks are assembled on local disk in `partial_folder`, only whole images go to storage. So
+// the requests of an upload must reach the same process, or processes sharing the folder.
+// One request at a time writes an upload, others get 423 until it is done.
+use crate::app_state::{upload_key, StoreState};
+use crate::handlers::*;
+use crate::ingest::{digest, process_image};
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use rocket::{
+    data::{Data, ToByteUnit},
+    http::{Header, Status},
+    outcome::Outcome,
+    request::{self, FromRequest, Request},
+    *,
+};
+use std::path::{Path, PathBuf};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::instrument;
+
+// Long enough for a chunk to arrive, short enough not to block a client retrying after a crash.
+const LOCK_SECONDS: u64 = 600;
+
+#[derive(Debug)]
+pub struct TusHeaders {
+    offset: Option<u64>,
+    length: Option<u64>,
+    digest: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders {
+    type Error = std::num::ParseIntError;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let parse = |name| {
+            req.headers()
+                .get_one(name)
+                .map(str::parse::<u64>)
+                .transpose()
+        };
+        match (parse("Upload-Offset"), parse("Upload-Length")) {
+            (Ok(offset), Ok(length)) => Outcome::Success(TusHeaders {
+                offset,
+                length,
+                digest: req.headers().get_one("Upload-Digest").map(str::to_string),
+            }),
+            (Err(e), _) | (_, Err(e)) => Outcome::Error((Status::BadRequest, e)),
+        }
+    }
+}
+
+#[derive(Responder)]
+pub struct TusResponse {
+    inner: (Status, String),
+    offset: Header<'static>,
+    length: Header<'static>,
+    cache_control: Header<'static>,
+    resumable: Header<'static>,
+}
+impl TusResponse {
+    fn new(result: anyhow::Result<(u64, u64)>, status: Status) -> Self {
+        let (inner, offset, length) = match result {
+            Ok((offset, length)) => ((status, String::new()), offset, length),
+            Err(e) => (error_response(e), 0, 0),
+        };
+        TusResponse {
+            inner,
+            offset: Header::new("Upload-Offset", offset.to_string()),
+            length: Header::new("Upload-Length", length.to_string()),
+            cache_control: Header::new("Cache-Control", "no-store"),
+            resumable: Header::new("Tus-Resumable", "1.0.0"),
+        }
+    }
+}
+
+fn partial_path(state: &StoreState, upload_id: &str) -> PathBuf {
+    state.partial_folder.join(upload_id)
+}
+
+fn parse_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        Err(BadRequest(format!("Bad digest {hex}")))?;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| BadRequest(format!("Bad digest {hex}")).into())
+        })
+        .collect()
+}
+
+fn lock_key(upload_id: &str) -> String {
+    format!("appending-{upload_id}")
+}
+
+// The token to give `unlock`, so a lock that expired and was taken since is left alone.
+async fn lock(state: &StoreState, upload_id: &str) -> anyhow::Result<String> {
+    let token = state.get_id();
+    let mut redis = state.redis_pool.aquire().await?;
+    let locked: Option<String> = redis
+        .set_options(
+            lock_key(upload_id),
+            &token,
+            SetOptions::default()
+                .conditional_set(ExistenceCheck::NX)
+                .with_expiration(SetExpiry::EX(LOCK_SECONDS)),
+        )
+        .await?;
+    match locked {
+        Some(_) => Ok(token),
+        None => Err(UploadBusy(upload_id.to_string()))?,
+    }
+}
+
+async fn unlock(state: &StoreState, upload_id: &str, token: &str) -> anyhow::Result<()> {
+    let mut redis = state.redis_pool.aquire().await?;
+    redis::Script::new(
+        r#"if redis.call("get", KEYS[1]) == ARGV[1] then return redis.call("del", KEYS[1]) end"#,
+    )
+    .key(lock_key(upload_id))
+    .arg(token)
+    .invoke_async::<()>(&mut *redis)
+    .await?;
+    Ok(())
+}
+
+async fn current_offset(partial: &Path) -> anyhow::Result<u64> {
+    match fs::metadata(partial).await {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e)?,
+    }
+}
+
+#[instrument]
+#[post("/<upload_id>")]
+pub async fn create(
+    state: &State<StoreState>,
+    upload_id: String,
+    headers: TusHeaders,
+) -> TusResponse {
+    let result: anyhow::Result<(u64, u64)> = try {
+        let mut pending = load_pending(state, &upload_id).await?;
+        let length = headers
+            .length
+            .ok_or(BadRequest("Upload-Length is required".to_string()))?;
+        if length > state.image_limits.max_bytes {
+            Err(ImageTooLarge(format!("{length} bytes")))?;
+        }
+        pending.upload_length = Some(length);
+        pending.expected_digest = headers.digest.as_deref().map(parse_hex).transpose()?;
+        fs::create_dir_all(&state.partial_folder).await?;
+        // Starting over must not truncate the file under a chunk being written.
+        let token = lock(state, &upload_id).await?;
+        let created = fs::File::create(partial_path(state, &upload_id)).await;
+        unlock(state, &upload_id, &token).await?;
+        created?;
+        save_pending(state, &upload_id, &pending).await?;
+        (0, length)
+    };
+    TusResponse::new(result, Status::Created)
+}
+
+#[instrument]
+#[head("/<upload_id>")]
+pub async fn offset(state: &State<StoreState>, upload_id: String) -> TusResponse {
+    let result: anyhow::Result<(u64, u64)> = try {
+        let pending = load_pending(state, &upload_id).await?;
+        let length = pending
+            .upload_length
+            .ok_or(BadRequest("Resumable upload not created".to_string()))?;
+        (
+            current_offset(&partial_path(state, &upload_id)).await?,
+            length,
+        )
+    };
+    TusResponse::new(result, Status::Ok)
+}
+
+// Bytes beyond `Upload-Length` are dropped. The last chunk moves the image into storage.
+#[instrument(skip(chunk))]
+#[patch("/<upload_id>", data = "<chunk>")]
+pub async fn append(
+    state: &State<StoreState>,
+    upload_id: String,
+    headers: TusHeaders,
+    chunk: Data<'_>,
+) -> TusResponse {
+    let result: anyhow::Result<(u64, u64)> = try {
+        let token = lock(state, &upload_id).await?;
+        let appended = append_chunk(state, &upload_id, headers, chunk).await;
+        unlock(state, &upload_id, &token).await?;
+        appended?
+    };
+    TusResponse::new(result, Status::NoContent)
+}
+
+// Under the lock, so the offset checked is still the end of the file when the chunk goes there.
+async fn append_chunk(
+    state: &StoreState,
+    upload_id: &str,
+    headers: TusHeaders,
+    chunk: Data<'_>,
+) -> anyhow::Result<(u64, u64)> {
+    let pending = load_pending(state, upload_id).await?;
+    let length = pending
+        .upload_length
+        .ok_or(BadRequest("Resumable upload not created".to_string()))?;
+    let partial = partial_path(state, upload_id);
+    let current = current_offset(&partial).await?;
+    if headers.offset != Some(current) {
+        Err(OffsetMismatch(current))?;
+    }
+
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&partial)
+        .await?;
+    let written = chunk
+        .open((length - current).bytes())
+        .stream_to(&mut file)
+        .await?;
+    file.flush().await?;
+    let offset = current + written.written;
+
+    let mut redis = state.redis_pool.aquire().await?;
+    redis
+        .expire(upload_key(upload_id), state.upload_session_ttl.try_into()?)
+        .await?;
+
+    if offset == length {
+        let content = fs::read(&partial).await?;
+        if pending
+            .expected_digest
+            .as_ref()
+            .is_some_and(|expected| *expected != digest(&content))
+        {
+            // Start over.
+            fs::File::create(&partial).await?;
+            Err(DigestMismatch)?;
+        }
+        let processed = process_image(content, &state.image_limits).await;
+        fs::remove_file(&partial).await?;
+        let (content, stored) = processed?;
+        put_image(state, &stored, content).await?;
+        finish_upload(state, upload_id, &stored, &pending).await?;
+    }
+    Ok((offset, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex_digests() {
+        assert_eq!(parse_hex("00ff7A").unwrap(), [0x00, 0xff, 0x7a]);
+        assert!(parse_hex("abc").unwrap_err().is::<BadRequest>());
+        assert!(parse_hex("zz").unwrap_err().is::<BadRequest>());
+        // SHA3-224 of nothing, as a client would send it.
+        let empty = "6b4e03423667dbb73b6e15454f0eb1abd4597f9a1b078e3f5b5a6bc7";
+        assert_eq!(parse_hex(empty).unwrap(), digest(b""));
+    }
+
+    #[tokio::test]
+    async fn offset_is_what_was_written() {
+        let partial = std::env::temp_dir().join(format!("jianai-{}", ulid::Ulid::new()));
+        assert_eq!(current_offset(&partial).await.unwrap(), 0);
+        fs::write(&partial, b"abcde").await.unwrap();
+        assert_eq!(current_offset(&partial).await.unwrap(), 5);
+        fs::remove_file(&partial).await.unwrap();
+    }
+}
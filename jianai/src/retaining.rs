@@ -0,0 +1,360 @@
+// Enforces `RetentionRules`, so years of feeder photos do not fill the storage.
+use crate::images::{encode_jpeg, THUMBNAIL_FOLDER};
+use crate::ingest::load_oriented;
+use crate::locking::{try_lock, unlock, RETAINING};
+use crate::model::Image;
+use crate::schema::*;
+use crate::sessioning::unsession;
+use crate::storage::Storage;
+use crate::types::{self, RetentionRules};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use diesel::{
+    dsl::{exists, not},
+    ExpressionMethods, QueryDsl, SelectableHelper,
+};
+use diesel_async::{
+    pooled_connection::bb8::Pool, scoped_futures::ScopedFutureExt, AsyncConnection,
+    AsyncPgConnection, RunQueryDsl,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+use tokio::task::spawn_blocking;
+use tracing::{error, info};
+
+// Under storage, crops of tagged segments as `<segment id>.jpg`.
+pub const CROP_FOLDER: &str = ".crops";
+// Under storage, tar files of removed originals.
+pub const ARCHIVE_FOLDER: &str = ".archive";
+// Images handled at a time, which also bounds the size of an archive.
+const BATCH: i64 = 500;
+
+pub async fn load_rules(path: &Path) -> Result<RetentionRules> {
+    Ok(serde_json::from_str(
+        &tokio::fs::read_to_string(path).await?,
+    )?)
+}
+
+pub async fn retaining_loop(
+    pg_pool: Pool<AsyncPgConnection>,
+    storage: Storage,
+    rules: RetentionRules,
+    retention_interval: u64,
+) {
+    loop {
+        if let Err(e) = retain(&pg_pool, &storage, &rules, false).await {
+            error!("Retention failed: {e:?}");
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(retention_interval)).await;
+    }
+}
+
+// With `dry_run`, only tells what would be removed. Otherwise one process at a time, as every
+// store process runs `retaining_loop`: the others skip the pass.
+pub async fn retain(
+    pg_pool: &Pool<AsyncPgConnection>,
+    storage: &Storage,
+    rules: &RetentionRules,
+    dry_run: bool,
+) -> Result<()> {
+    let mut pg_conn = pg_pool.get().await?;
+    if dry_run {
+        return remove(&mut pg_conn, storage, rules, true).await;
+    }
+    if !try_lock(&mut pg_conn, RETAINING).await? {
+        info!("Retention is being enforced by another process");
+        return Ok(());
+    }
+    let result = remove(&mut pg_conn, storage, rules, false).await;
+    let unlocked = unlock(&mut pg_conn, RETAINING).await;
+    result?;
+    Ok(unlocked?)
+}
+
+async fn remove(
+    pg_conn: &mut AsyncPgConnection,
+    storage: &Storage,
+    rules: &RetentionRules,
+    dry_run: bool,
+) -> Result<()> {
+    let now = Utc::now();
+    let doing = if dry_run { "Would remove" } else { "Removing" };
+    let mut removed_files = Vec::new();
+
+    if let Some(days) = rules.catless_days {
+        let mut last_id = 0;
+        let mut count = 0;
+        loop {
+            let batch: Vec<Image> = images::table
+                .filter(images::id.gt(last_id))
+                .filter(images::segmented.eq(true))
+                .filter(images::captured_at.lt(now - Duration::days(days.into())))
+                .filter(not(exists(
+                    segments::table.filter(segments::image_id.eq(images::id)),
+                )))
+                .select(Image::as_select())
+                .order(images::id)
+                .limit(BATCH)
+                .load(pg_conn)
+                .await?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            last_id = last.id;
+            count += batch.len();
+            for image in &batch {
+                info!("{doing} image {} without cats", image.id);
+            }
+            if dry_run {
+                continue;
+            }
+            if rules.archive {
+                archive(storage, &batch).await?;
+            }
+            let ids: Vec<i32> = batch.iter().map(|x| x.id).collect();
+            // Sessions spanning them are redone without them.
+            let batch = pg_conn
+                .transaction(|pg_conn| {
+                    (async move {
+                        diesel::delete(images::table.filter(images::id.eq_any(&ids)))
+                            .execute(pg_conn)
+                            .await?;
+                        unsession(pg_conn, &batch).await?;
+                        Ok(batch) as Result<Vec<Image>, diesel::result::Error>
+                    })
+                    .scope_boxed()
+                })
+                .await?;
+            removed_files.extend(batch.into_iter().map(|x| x.filename));
+        }
+        info!("{count} images without cats older than {days} days");
+    }
+
+    if let Some(days) = rules.originals_days {
+        let mut last_id = 0;
+        let mut count = 0;
+        loop {
+            // Not before the segmenter had it, nor counting from an old capture time alone.
+            let cutoff = now - Duration::days(days.into());
+            let batch: Vec<Image> = images::table
+                .filter(images::id.gt(last_id))
+                .filter(images::expired_at.is_null())
+                .filter(images::segmented.eq(true))
+                .filter(images::captured_at.lt(cutoff))
+                .filter(images::uploaded_at.lt(cutoff))
+                .select(Image::as_select())
+                .order(images::id)
+                .limit(BATCH)
+                .load(pg_conn)
+                .await?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            last_id = last.id;
+            count += batch.len();
+            let ids: Vec<i32> = batch.iter().map(|x| x.id).collect();
+            let tagged: Vec<(i32, i32, types::Box)> = if rules.keep_tagged_crops {
+                segments::table
+                    .filter(segments::image_id.eq_any(&ids))
+                    .filter(segments::tagged_as.is_not_null())
+                    .select((segments::image_id, segments::id, segments::bounding_box))
+                    .load(pg_conn)
+                    .await?
+            } else {
+                Vec::new()
+            };
+            let mut crops: HashMap<i32, Vec<(i32, types::Box)>> = HashMap::new();
+            for (image_id, segment_id, bounding_box) in tagged {
+                crops
+                    .entry(image_id)
+                    .or_default()
+                    .push((segment_id, bounding_box));
+            }
+            for image in &batch {
+                let kept = crops.get(&image.id).map_or(0, Vec::len);
+                info!(
+                    "{doing} the original of image {}, keeping {kept} crops",
+                    image.id
+                );
+            }
+            if dry_run {
+                continue;
+            }
+
+            for image in &batch {
+                let Some(boxes) = crops.remove(&image.id) else {
+                    continue;
+                };
+                let original = storage.get(&image.filename).await?;
+                let cropped = spawn_blocking(move || crop(&original, boxes)).await??;
+                for (segment_id, content) in cropped {
+                    storage
+                        .put(&format!("{CROP_FOLDER}/{segment_id}.jpg"), content)
+                        .await?;
+                }
+            }
+            if rules.archive {
+                archive(storage, &batch).await?;
+            }
+            diesel::update(images::table.filter(images::id.eq_any(&ids)))
+                .set(images::expired_at.eq(now))
+                .execute(pg_conn)
+                .await?;
+            removed_files.extend(batch.into_iter().map(|x| x.filename));
+        }
+        info!("{count} originals older than {days} days");
+    }
+
+    if removed_files.is_empty() {
+        return Ok(());
+    }
+    // Identical uploads share a file, which stays while any of them keeps its original.
+    removed_files.sort();
+    removed_files.dedup();
+    let needed: HashSet<String> = images::table
+        .filter(images::filename.eq_any(&removed_files))
+        .filter(images::expired_at.is_null())
+        .select(images::filename)
+        .load::<String>(pg_conn)
+        .await?
+        .into_iter()
+        .collect();
+    // Thumbnails are kept for images still around, for dashboards to show.
+    let shown: HashSet<String> = images::table
+        .filter(images::filename.eq_any(&removed_files))
+        .select(images::filename)
+        .load::<String>(pg_conn)
+        .await?
+        .into_iter()
+        .collect();
+    let thumbnails = storage.list(Some(THUMBNAIL_FOLDER)).await?;
+    for filename in removed_files.iter().filter(|x| !needed.contains(*x)) {
+        storage.delete(filename).await?;
+        if shown.contains(filename) {
+            continue;
+        }
+        let suffix = format!("/{filename}.jpg");
+        for thumbnail in thumbnails.iter().filter(|x| x.name.ends_with(&suffix)) {
+            storage.delete(&thumbnail.name).await?;
+        }
+    }
+    Ok(())
+}
+
+pub fn crop(original: &[u8], boxes: Vec<(i32, types::Box)>) -> Result<Vec<(i32, Vec<u8>)>> {
+    let image = load_oriented(original)?;
+    boxes
+        .into_iter()
+        .map(|(segment_id, bounding_box)| {
+            let cropped = image.crop_imm(
+                bounding_box.point1.x.max(0.0) as u32,
+                bounding_box.point1.y.max(0.0) as u32,
+                bounding_box.width().max(1.0) as u32,
+                bounding_box.height().max(1.0) as u32,
+            );
+            Ok((segment_id, encode_jpeg(cropped)?))
+        })
+        .collect()
+}
+
+// The originals still there, with the rows of all in `images.json`.
+async fn archive(storage: &Storage, images: &[Image]) -> Result<()> {
+    let now = Utc::now();
+    let mut builder = tar::Builder::new(Vec::new());
+    append(
+        &mut builder,
+        "images.json",
+        &serde_json::to_vec_pretty(images)?,
+        now,
+    )?;
+    let mut archived = HashSet::new();
+    for image in images.iter().filter(|x| x.expired_at.is_none()) {
+        if archived.insert(&image.filename) {
+            let content = storage.get(&image.filename).await?;
+            append(&mut builder, &image.filename, &content, now)?;
+        }
+    }
+    let name = format!(
+        "{ARCHIVE_FOLDER}/{}-{}.tar",
+        now.format("%Y%m%dT%H%M%S"),
+        images.first().map_or(0, |x| x.id)
+    );
+    storage.put(&name, builder.into_inner()?).await?;
+    info!("Archived {} images to {name}", images.len());
+    Ok(())
+}
+
+// Takes the images out of the archives having them: their rows from `images.json` and their
+// originals unless another image left in the same archive has the file. Archives left with no
+// images are deleted.
+pub async fn unarchive(storage: &Storage, ids: &HashSet<i32>) -> Result<()> {
+    for object in storage.list(Some(ARCHIVE_FOLDER)).await? {
+        let content = storage.get(&object.name).await?;
+        let mut entries = Vec::new();
+        for entry in tar::Archive::new(&content[..]).entries()? {
+            let mut entry = entry?;
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut data)?;
+            entries.push((
+                entry.header().clone(),
+                entry.path()?.to_string_lossy().into_owned(),
+                data,
+            ));
+        }
+        let Some(index) = entries.iter().position(|x| x.1 == "images.json") else {
+            continue;
+        };
+        let rows: Vec<serde_json::Value> = serde_json::from_slice(&entries[index].2)?;
+        let id = |x: &serde_json::Value| {
+            x["id"]
+                .as_i64()
+                .and_then(|x| i32::try_from(x).ok())
+                .unwrap_or(0)
+        };
+        if !rows.iter().any(|x| ids.contains(&id(x))) {
+            continue;
+        }
+        let rows: Vec<_> = rows.into_iter().filter(|x| !ids.contains(&id(x))).collect();
+        if rows.is_empty() {
+            storage.delete(&object.name).await?;
+            info!("Deleted {} with the last of its images", object.name);
+            continue;
+        }
+        let kept: HashSet<&str> = rows.iter().filter_map(|x| x["filename"].as_str()).collect();
+        let mut builder = tar::Builder::new(Vec::new());
+        for (header, name, data) in &entries {
+            if name == "images.json" {
+                let modified = DateTime::from_timestamp(header.mtime()?.try_into()?, 0)
+                    .unwrap_or_else(Utc::now);
+                append(
+                    &mut builder,
+                    name,
+                    &serde_json::to_vec_pretty(&rows)?,
+                    modified,
+                )?;
+            } else if kept.contains(name.as_str()) {
+                builder.append(header, &data[..])?;
+            }
+        }
+        storage.put(&object.name, builder.into_inner()?).await?;
+        info!("Took deleted images out of {}", object.name);
+    }
+    Ok(())
+}
+
+fn append(
+    builder: &mut tar::Builder<Vec<u8>>,
+    name: &str,
+    content: &[u8],
+    modified: DateTime<Utc>,
+) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(modified.timestamp().try_into()?);
+    header.set_cksum();
+    builder.append_data(&mut header, name, content)?;
+    Ok(())
+}
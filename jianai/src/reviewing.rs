@@ -0,0 +1,468 @@
+// Leases reviewers the segments whose tags would teach the identifier the most first: where it
+// is unsure, where its top two cats are close, and where it disagrees with the feeding session
+// the frame is in. Answers go into `tagged_as` and are kept in `reviews` for throughput.
+use crate::app_state::StoreState;
+use crate::handlers::*;
+use crate::images::User;
+use crate::labeling::{apply_label, labeled};
+use crate::model::{
+    FeedingSession, Lease, NewReview, Review, Segment, SegmentLabel, SegmentWithTag,
+};
+use crate::schema::*;
+use crate::types::ReviewerStats;
+use chrono::{DateTime, Duration, Utc};
+use diesel::{
+    dsl::{exists, not},
+    BoolExpressionMethods, ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper,
+};
+use diesel_async::{
+    scoped_futures::ScopedFutureExt, AsyncConnection, AsyncPgConnection, RunQueryDsl,
+};
+use rocket::{http::Status, serde::json::Json, *};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use tracing::instrument;
+
+// Untagged segments looked at per lease, newest first.
+const CANDIDATES: i64 = 1000;
+const LEASE_SECONDS: i64 = 300;
+
+// Segment id, image id, camera, capture time, `identified_as`, detection confidence and
+// identification scores. Like `identified_as`, the scores are the identifier's to write, a
+// JSON object of tag id to score, and may well be missing.
+type Candidate = (
+    i32,
+    i32,
+    Option<i32>,
+    DateTime<Utc>,
+    Option<i32>,
+    Option<f32>,
+    Option<Value>,
+);
+
+// Segment id, camera, capture time and cat of a segment with a cat to it, tagged or identified.
+type Sighting = (i32, Option<i32>, DateTime<Utc>, i32);
+
+// Higher is more worth a human look.
+fn prioritize(
+    candidate: &Candidate,
+    sessions: &[FeedingSession],
+    sightings: &[Sighting],
+) -> (f32, Vec<&'static str>) {
+    let (id, _, camera_id, captured_at, identified_as, confidence, scores) = candidate;
+    let mut priority = 0.0;
+    let mut reasons = Vec::new();
+    let mut scores: Vec<f32> = scores
+        .as_ref()
+        .and_then(|x| serde_json::from_value::<HashMap<String, f32>>(x.clone()).ok())
+        .map(|x| x.into_values().collect())
+        .unwrap_or_default();
+    scores.sort_by(|a, b| b.total_cmp(a));
+    match scores[..] {
+        [] => {
+            // No scores, so only how sure the detector was tells anything.
+            priority += 1.0 - confidence.unwrap_or(0.5);
+            if identified_as.is_none() {
+                reasons.push("not identified");
+            }
+        }
+        [top, ref rest @ ..] => {
+            let margin = top - rest.first().copied().unwrap_or(0.0);
+            priority += (1.0 - top) + (1.0 - margin);
+            if top < 0.5 {
+                reasons.push("low identification confidence");
+            }
+            if margin < 0.2 {
+                reasons.push("close top two tags");
+            }
+        }
+    }
+    // The frame is in feeding sessions, but no other segment in them is of the identified cat.
+    // The candidate itself puts its cat into a session, so it is left out. Another cat eating
+    // alongside is no disagreement, nor is a frame nothing identified.
+    let covering: Vec<_> = sessions
+        .iter()
+        .filter(|x| {
+            Some(x.camera_id) == *camera_id
+                && x.started_at <= *captured_at
+                && *captured_at <= x.ended_at
+        })
+        .collect();
+    let others: Vec<_> = sightings
+        .iter()
+        .filter(|x| {
+            x.0 != *id
+                && x.1 == *camera_id
+                && covering
+                    .iter()
+                    .any(|session| session.started_at <= x.2 && x.2 <= session.ended_at)
+        })
+        .collect();
+    let disagrees = identified_as.is_some_and(|identified_as| {
+        !others.is_empty() && others.iter().all(|x| x.3 != identified_as)
+    });
+    if disagrees {
+        priority += 1.0;
+        reasons.push("disagrees with its feeding session");
+    }
+    (priority, reasons)
+}
+
+#[derive(Responder)]
+pub enum NextReview {
+    Leased(Json<Box<Lease>>),
+    #[response(status = 204)]
+    Done(()),
+}
+
+// The reviewer's current lease if any, so asking again does not hoard segments.
+#[instrument]
+#[post("/next")]
+pub async fn next_review(
+    state: &State<StoreState>,
+    user: User,
+) -> Result<NextReview, (Status, String)> {
+    let result: anyhow::Result<Option<Lease>> = try {
+        let User(reviewer) = user;
+        let now = Utc::now();
+        let mut pg_conn = state.pg_pool.get().await?;
+        // Expired leases are let go, not kept as history.
+        diesel::delete(
+            reviews::table
+                .filter(reviews::answered_at.is_null())
+                .filter(reviews::expires_at.lt(now)),
+        )
+        .execute(&mut pg_conn)
+        .await?;
+        let held: Option<Review> = reviews::table
+            .filter(reviews::reviewer.eq(&reviewer))
+            .filter(reviews::answered_at.is_null())
+            .select(Review::as_select())
+            .first(&mut pg_conn)
+            .await
+            .optional()?;
+        let leased = match held {
+            Some(review) => Some((review, Vec::new())),
+            None => lease(&mut pg_conn, &reviewer, now).await?,
+        };
+        match leased {
+            Some((review, reasons)) => {
+                let segment: Vec<_> = segments::table
+                    .find(review.segment_id)
+                    .select(Segment::as_select())
+                    .load(&mut pg_conn)
+                    .await?;
+                let segment = SegmentWithTag::with_tags(&mut pg_conn, segment)
+                    .await?
+                    .remove(0);
+                Some(Lease {
+                    review,
+                    reasons,
+                    segment,
+                })
+            }
+            None => None,
+        }
+    };
+    result
+        .map(|x| {
+            x.map_or(NextReview::Done(()), |x| {
+                NextReview::Leased(Json(Box::new(x)))
+            })
+        })
+        .map_err(error_response)
+}
+
+async fn lease(
+    pg_conn: &mut AsyncPgConnection,
+    reviewer: &str,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<(Review, Vec<&'static str>)>> {
+    let candidates: Vec<Candidate> = segments::table
+        .inner_join(images::table)
+        .filter(segments::tagged_as.is_null())
+        .filter(segments::low_quality.eq(false))
+        .filter(images::expired_at.is_null())
+        .filter(not(exists(
+            reviews::table
+                .filter(reviews::segment_id.eq(segments::id))
+                .filter(reviews::answered_at.is_null()),
+        )))
+        .select((
+            segments::id,
+            images::id,
+            images::camera_id,
+            images::captured_at,
+            segments::identified_as,
+            segments::confidence,
+            segments::identification_scores,
+        ))
+        .order(segments::id.desc())
+        .limit(CANDIDATES)
+        .load(pg_conn)
+        .await?;
+    let (Some(from), Some(to)) = (
+        candidates.iter().map(|x| x.3).min(),
+        candidates.iter().map(|x| x.3).max(),
+    ) else {
+        return Ok(None);
+    };
+    let sessions: Vec<FeedingSession> = feeding_sessions::table
+        .filter(feeding_sessions::started_at.le(to))
+        .filter(feeding_sessions::ended_at.ge(from))
+        .select(FeedingSession::as_select())
+        .load(pg_conn)
+        .await?;
+    let mut sightings: Vec<Sighting> = Vec::new();
+    if let (Some(from), Some(to)) = (
+        sessions.iter().map(|x| x.started_at).min(),
+        sessions.iter().map(|x| x.ended_at).max(),
+    ) {
+        // A human tag wins over what the identifier thinks, as in sessioning.
+        sightings = segments::table
+            .inner_join(images::table)
+            .filter(images::captured_at.between(from, to))
+            .filter(
+                segments::tagged_as
+                    .is_not_null()
+                    .or(segments::identified_as.is_not_null()),
+            )
+            .select((
+                segments::id,
+                images::camera_id,
+                images::captured_at,
+                segments::tagged_as,
+                segments::identified_as,
+            ))
+            .load::<(i32, Option<i32>, DateTime<Utc>, Option<i32>, Option<i32>)>(pg_conn)
+            .await?
+            .into_iter()
+            .filter_map(|(id, camera_id, captured_at, tagged_as, identified_as)| {
+                Some((id, camera_id, captured_at, tagged_as.or(identified_as)?))
+            })
+            .collect();
+    }
+    let mut ranked: Vec<_> = candidates
+        .iter()
+        .map(|x| (x.0, prioritize(x, &sessions, &sightings)))
+        .collect();
+    ranked.sort_by(|a, b| b.1 .0.total_cmp(&a.1 .0));
+    for (segment_id, (priority, reasons)) in ranked {
+        // Another reviewer may have taken it meanwhile.
+        let review: Option<Review> = diesel::insert_into(reviews::table)
+            .values(NewReview {
+                segment_id,
+                reviewer: reviewer.to_string(),
+                priority,
+                expires_at: now + Duration::seconds(LEASE_SECONDS),
+            })
+            .on_conflict_do_nothing()
+            .returning(Review::as_returning())
+            .get_result(pg_conn)
+            .await
+            .optional()?;
+        if let Some(review) = review {
+            return Ok(Some((review, reasons)));
+        }
+    }
+    Ok(None)
+}
+
+// Only while the lease lasts. Once expired it is as good as let go, whether or not
+// `/review/next` has deleted it yet.
+#[instrument]
+#[post("/<id>", format = "application/json", data = "<label>")]
+pub async fn answer_review(
+    state: &State<StoreState>,
+    user: User,
+    id: i32,
+    label: Json<SegmentLabel>,
+) -> Result<Json<Review>, (Status, String)> {
+    let result: anyhow::Result<Review> = try {
+        let User(reviewer) = user;
+        let label = label.into_inner();
+        let mut pg_conn = state.pg_pool.get().await?;
+        let (review, segment, previous) = pg_conn
+            .transaction(|pg_conn| {
+                (async move {
+                    let review: Option<Review> = reviews::table
+                        .find(id)
+                        .filter(reviews::reviewer.eq(&reviewer))
+                        .filter(reviews::answered_at.is_null())
+                        .filter(reviews::expires_at.ge(Utc::now()))
+                        .select(Review::as_select())
+                        .for_update()
+                        .first(pg_conn)
+                        .await
+                        .optional()?;
+                    let Some(review) = review else {
+                        Err(LeaseLost(id))?
+                    };
+                    let answered = (
+                        reviews::answered_at.eq(Utc::now()),
+                        reviews::tagged_as.eq(label.tagged_as),
+                        reviews::low_quality.eq(label.low_quality),
+                    );
+                    let (segment, previous) =
+                        apply_label(pg_conn, review.segment_id, label).await?;
+                    let review = diesel::update(reviews::table.find(id))
+                        .set(answered)
+                        .returning(Review::as_returning())
+                        .get_result(pg_conn)
+                        .await?;
+                    Ok((review, segment, previous)) as anyhow::Result<_>
+                })
+                .scope_boxed()
+            })
+            .await?;
+        labeled(state, &mut pg_conn, &segment, previous).await;
+        review
+    };
+    result.map(Json).map_err(error_response)
+}
+
+// Times are RFC 3339, `from` inclusive and `to` exclusive, on when the answer came.
+#[instrument]
+#[get("/throughput?<from>&<to>")]
+pub async fn throughput(
+    state: &State<StoreState>,
+    from: Option<String>,
+    to: Option<String>,
+) -> Result<Json<Vec<ReviewerStats>>, (Status, String)> {
+    let result: anyhow::Result<Vec<ReviewerStats>> = try {
+        let mut query = reviews::table
+            .filter(reviews::answered_at.is_not_null())
+            .select(Review::as_select())
+            .into_boxed();
+        if let Some(from) = from {
+            query = query.filter(reviews::answered_at.ge(parse_time(&from)?));
+        }
+        if let Some(to) = to {
+            query = query.filter(reviews::answered_at.lt(parse_time(&to)?));
+        }
+        let mut pg_conn = state.pg_pool.get().await?;
+        let answered: Vec<Review> = query.load(&mut pg_conn).await?;
+        let mut stats: BTreeMap<String, ReviewerStats> = BTreeMap::new();
+        for review in answered {
+            let Some(answered_at) = review.answered_at else {
+                continue;
+            };
+            let x = stats
+                .entry(review.reviewer.clone())
+                .or_insert_with(|| ReviewerStats {
+                    reviewer: review.reviewer.clone(),
+                    answered: 0,
+                    tagged: 0,
+                    low_quality: 0,
+                    average_seconds: 0.0,
+                    first_answered_at: None,
+                    last_answered_at: None,
+                });
+            x.answered += 1;
+            x.tagged += i64::from(review.tagged_as.is_some());
+            x.low_quality += i64::from(review.low_quality == Some(true));
+            // Summed here, averaged below.
+            x.average_seconds +=
+                (answered_at - review.leased_at).num_milliseconds() as f64 / 1000.0;
+            x.first_answered_at = Some(
+                x.first_answered_at
+                    .map_or(answered_at, |y| y.min(answered_at)),
+            );
+            x.last_answered_at = Some(
+                x.last_answered_at
+                    .map_or(answered_at, |y| y.max(answered_at)),
+            );
+        }
+        stats
+            .into_values()
+            .map(|mut x| {
+                x.average_seconds /= x.answered as f64;
+                x
+            })
+            .collect()
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn at(minute: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap() + Duration::minutes(minute)
+    }
+
+    fn candidate(identified_as: Option<i32>, scores: Option<Value>) -> Candidate {
+        (1, 1, Some(1), at(5), identified_as, Some(0.9), scores)
+    }
+
+    fn session(camera_id: i32, tag_id: i32, from: i32, to: i32) -> FeedingSession {
+        FeedingSession {
+            id: 1,
+            camera_id,
+            tag_id,
+            started_at: at(from.into()),
+            ended_at: at(to.into()),
+            duration_seconds: (to - from) * 60,
+            frame_count: 2,
+        }
+    }
+
+    #[test]
+    fn unidentified_goes_by_detection_confidence() {
+        let (priority, reasons) = prioritize(&candidate(None, None), &[session(1, 2, 0, 10)], &[]);
+        assert!((priority - 0.1).abs() < 1e-6);
+        assert_eq!(reasons, ["not identified"]);
+    }
+
+    #[test]
+    fn close_scores_come_first() {
+        let (close, reasons) = prioritize(
+            &candidate(Some(2), Some(json!({"2": 0.45, "3": 0.4}))),
+            &[],
+            &[],
+        );
+        assert_eq!(
+            reasons,
+            ["low identification confidence", "close top two tags"]
+        );
+        let (sure, reasons) = prioritize(&candidate(Some(2), Some(json!({"2": 0.95}))), &[], &[]);
+        assert!(reasons.is_empty());
+        assert!(close > sure);
+    }
+
+    // As sessioning leaves them: the candidate, segment 1 at minute 5 identified as cat 2, is in a
+    // session of its own.
+    #[test]
+    fn disagrees_only_when_other_segments_are_of_other_cats() {
+        let disagrees = |sessions: &[FeedingSession], sightings: &[Sighting]| {
+            prioritize(
+                &candidate(Some(2), Some(json!({"2": 0.95}))),
+                sessions,
+                sightings,
+            )
+            .1
+            .contains(&"disagrees with its feeding session")
+        };
+        let own = (1, Some(1), at(5), 2);
+        let others = [(2, Some(1), at(4), 3), (3, Some(1), at(6), 3)];
+        assert!(disagrees(
+            &[session(1, 3, 4, 6), session(1, 2, 5, 5)],
+            &[own, others[0], others[1]]
+        ));
+        // Another cat eating alongside.
+        assert!(!disagrees(
+            &[session(1, 3, 4, 6), session(1, 2, 5, 7)],
+            &[own, others[0], others[1], (4, Some(1), at(7), 2)]
+        ));
+        // Nothing else seen.
+        assert!(!disagrees(&[session(1, 2, 5, 5)], &[own]));
+        // Another camera.
+        assert!(!disagrees(
+            &[session(2, 3, 4, 6)],
+            &[(2, Some(2), at(4), 3), (3, Some(2), at(6), 3)]
+        ));
+        assert!(!disagrees(&[], &[]));
+    }
+}
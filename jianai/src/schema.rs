@@ -6,6 +6,63 @@ pub mod sql_types {
     pub struct Box;
 }
 
+diesel::table! {
+    alert_rules (id) {
+        id -> Int4,
+        name -> Text,
+        condition -> Jsonb,
+        enabled -> Bool,
+    }
+}
+
+diesel::table! {
+    alerts (id) {
+        id -> Int4,
+        rule_id -> Int4,
+        message -> Text,
+        fired_at -> Timestamptz,
+        resolved_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    cameras (id) {
+        id -> Int4,
+        name -> Text,
+        location -> Nullable<Text>,
+        width -> Nullable<Int4>,
+        height -> Nullable<Int4>,
+        zones -> Nullable<Jsonb>,
+        timezone -> Text,
+    }
+}
+
+diesel::table! {
+    deletions (id) {
+        id -> Int4,
+        image_id -> Int4,
+        filename -> Text,
+        digest -> Bytea,
+        camera_id -> Nullable<Int4>,
+        captured_at -> Timestamptz,
+        deleted_by -> Text,
+        reason -> Nullable<Text>,
+        deleted_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    feeding_sessions (id) {
+        id -> Int4,
+        camera_id -> Int4,
+        tag_id -> Int4,
+        started_at -> Timestamptz,
+        ended_at -> Timestamptz,
+        duration_seconds -> Int4,
+        frame_count -> Int4,
+    }
+}
+
 diesel::table! {
     images (id) {
         id -> Int4,
@@ -13,6 +70,29 @@ diesel::table! {
         digest -> Bytea,
         metadata -> Nullable<Jsonb>,
         segmented -> Bool,
+        camera_id -> Nullable<Int4>,
+        captured_at -> Timestamptz,
+        upload_id -> Text,
+        width -> Nullable<Int4>,
+        height -> Nullable<Int4>,
+        format -> Nullable<Text>,
+        expired_at -> Nullable<Timestamptz>,
+        sessioned -> Bool,
+        uploaded_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    reviews (id) {
+        id -> Int4,
+        segment_id -> Int4,
+        reviewer -> Text,
+        priority -> Float4,
+        leased_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        answered_at -> Nullable<Timestamptz>,
+        tagged_as -> Nullable<Int4>,
+        low_quality -> Nullable<Bool>,
     }
 }
 
@@ -27,6 +107,9 @@ diesel::table! {
         identified_as -> Nullable<Int4>,
         tagged_as -> Nullable<Int4>,
         low_quality -> Bool,
+        confidence -> Nullable<Float4>,
+        identification_scores -> Nullable<Jsonb>,
+        identified_by -> Nullable<Text>,
     }
 }
 
@@ -37,10 +120,50 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        event -> Text,
+        payload -> Jsonb,
+        created_at -> Timestamptz,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        delivered_at -> Nullable<Timestamptz>,
+        failed_at -> Nullable<Timestamptz>,
+        status_code -> Nullable<Int4>,
+        last_error -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        url -> Text,
+        secret -> Text,
+        events -> Array<Text>,
+        enabled -> Bool,
+    }
+}
+
+diesel::joinable!(alerts -> alert_rules (rule_id));
+diesel::joinable!(feeding_sessions -> cameras (camera_id));
+diesel::joinable!(feeding_sessions -> tags (tag_id));
+diesel::joinable!(images -> cameras (camera_id));
+diesel::joinable!(reviews -> segments (segment_id));
 diesel::joinable!(segments -> images (image_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    alert_rules,
+    alerts,
+    cameras,
+    deletions,
+    feeding_sessions,
     images,
+    reviews,
     segments,
     tags,
+    webhook_deliveries,
+    webhooks,
 );
@@ -1,6 +1,11 @@
+use crate::ingest::load_oriented;
 use crate::model::*;
+use crate::publishing::Publisher;
 use crate::schema::*;
+use crate::storage::Storage;
+use crate::streaming;
 use crate::types;
+use crate::webhooking::{self, IMAGE_SEGMENTED};
 use anyhow::{anyhow, Result};
 use diesel::{ExpressionMethods, QueryDsl};
 use diesel_async::{
@@ -14,22 +19,26 @@ use redis::{
     aio::MultiplexedConnection, AsyncCommands, Client, ExistenceCheck, RedisResult, SetOptions,
 };
 use redis_pool::RedisPool;
-use std::{fs, path::PathBuf, sync::Arc};
+use serde_json::{json, Value};
+use std::{path::PathBuf, sync::Arc};
 use tokio::task::spawn_blocking;
-use tracing::info;
+use tracing::{error, info};
 
 #[async_recursion::async_recursion]
 pub async fn segmenting_loop(
     redis_pool: RedisPool<Client, MultiplexedConnection>,
     pg_pool: Pool<AsyncPgConnection>,
-    image_folder: PathBuf,
+    storage: Storage,
     model_path: PathBuf,
+    threshold: f32,
+    publisher: Option<Publisher>,
 ) -> Result<()> {
     info!("Preparing segmenting");
     use diesel_async::RunQueryDsl;
     let mut pg_conn = pg_pool.get().await?;
     let untagged_images: Vec<Image> = images::dsl::images
         .filter(images::segmented.eq(false))
+        .filter(images::expired_at.is_null())
         .load(&mut pg_conn)
         .await?;
 
@@ -73,20 +82,19 @@ pub async fn segmenting_loop(
 
     if let Some(image) = todo {
         info!("Found image to segment");
-        let image1 = Image {
-            filename: image_folder
-                .join(image.filename.clone())
-                .to_str()
-                .expect("msg")
-                .to_string(),
-            ..image.clone()
+        let segmented = match storage.get(&image.filename).await {
+            Ok(content) => {
+                let model_path1 = model_path.clone();
+                spawn_blocking(move || segmenting(content, model_path1, threshold)).await?
+            }
+            Err(e) => Err(e),
         };
         let image = Arc::new(image);
-        let model_path1 = model_path.clone();
-        match spawn_blocking(|| segmenting(image1, model_path1)).await? {
+        match segmented {
             Ok(segments) => {
                 info!("Segmenting done. Updating DB.");
-                pg_conn
+                let image1 = image.clone();
+                let segments: Vec<SegmentWithTag> = pg_conn
                     .transaction(|pg_conn| {
                         (async move {
                             let inserts: Vec<_> = segments
@@ -96,6 +104,7 @@ pub async fn segmenting_loop(
                                     (
                                         segments::image_id.eq(image.id),
                                         segments::bounding_box.eq(segment.bounding_box),
+                                        segments::confidence.eq(segment.posibility),
                                     )
                                 })
                                 .collect();
@@ -107,7 +116,10 @@ pub async fn segmenting_loop(
                                 .set(images::segmented.eq(true))
                                 .get_result::<Image>(pg_conn)
                                 .await?;
-                            Ok(()) as Result<(), diesel::result::Error>
+                            let segments = SegmentWithTag::for_images(pg_conn, &[image.id]).await?;
+                            let payload = segmented_payload(&image, &segments);
+                            webhooking::enqueue(pg_conn, IMAGE_SEGMENTED, payload).await?;
+                            Ok(segments) as Result<_, diesel::result::Error>
                         })
                         .scope_boxed()
                     })
@@ -115,10 +127,22 @@ pub async fn segmenting_loop(
 
                 redis.del(mc_key).await?;
 
+                let payload = segmented_payload(&image1, &segments);
+                streaming::publish(&redis_pool, IMAGE_SEGMENTED, payload).await;
+                // The broker being away should not hold up segmenting.
+                if let Some(publisher) = &publisher {
+                    if let Err(e) = publisher.segmented(&mut pg_conn, &image1, &segments).await {
+                        error!("Publishing image {} failed: {e:?}", image1.id);
+                    }
+                }
+
                 drop(pg_conn);
                 drop(redis);
                 info!("Start next round");
-                segmenting_loop(redis_pool, pg_pool, image_folder, model_path).await?;
+                segmenting_loop(
+                    redis_pool, pg_pool, storage, model_path, threshold, publisher,
+                )
+                .await?;
             }
             e => {
                 redis.del(mc_key).await?;
@@ -130,9 +154,19 @@ pub async fn segmenting_loop(
     Ok(())
 }
 
-fn segmenting(image: Image, model_path: PathBuf) -> Result<Vec<Segment>> {
-    let file = fs::read(image.filename.clone())?;
-    let image = image::load_from_memory(&file)?;
+fn segmented_payload(image: &Image, segments: &[SegmentWithTag]) -> Value {
+    json!({
+        "image_id": image.id,
+        "camera_id": image.camera_id,
+        "captured_at": image.captured_at,
+        "segments": segments,
+    })
+}
+
+// Detections less sure than `threshold` are dropped.
+pub fn segmenting(content: Vec<u8>, model_path: PathBuf, threshold: f32) -> Result<Vec<Segment>> {
+    // Boxes are in the coordinates users see.
+    let image = load_oriented(&content)?;
     let mut input = Array::zeros((1, 3, image.height().try_into()?, image.width().try_into()?));
 
     // Array: FromIterator only suports one dimensional array.
@@ -180,7 +214,7 @@ fn segmenting(image: Image, model_path: PathBuf) -> Result<Vec<Segment>> {
             let yc = *row.get([1_usize]).expect("msg");
             let w = *row.get([2_usize]).expect("msg");
             let h = *row.get([3_usize]).expect("msg");
-            if *prob < 0.5 {
+            if *prob < threshold {
                 None
             } else {
                 Some((
@@ -211,19 +245,19 @@ fn segmenting(image: Image, model_path: PathBuf) -> Result<Vec<Segment>> {
         result.push(Segment {
             bounding_box: a,
             class: b.to_string(),
-            _posibility: c,
+            posibility: c,
         });
-        boxes.retain(|&(box1, _, _)| a.intersection_area(&box1) / a.union_area(&box1) < 0.7);
+        boxes.retain(|&(box1, _, _)| a.iou(&box1) < 0.7);
     }
 
     Ok(result)
 }
 
 #[derive(Clone)]
-struct Segment {
-    bounding_box: types::Box,
-    class: String,
-    _posibility: f32,
+pub struct Segment {
+    pub bounding_box: types::Box,
+    pub class: String,
+    pub posibility: f32,
 }
 
 const YOLOV8_CLASS_LABELS: [&str; 80] = [
@@ -0,0 +1,206 @@
+// Groups frames of the same cat at the same camera into feeding sessions, consecutive frames
+// being at most `session_gap` apart. Only the time around newly segmented images is redone.
+use crate::locking::{try_xact_lock, SESSIONING};
+use crate::model::{Image, NewFeedingSession};
+use crate::schema::*;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use diesel::{dsl, ExpressionMethods, QueryDsl, QueryResult};
+use diesel_async::{
+    pooled_connection::bb8::Pool, scoped_futures::ScopedFutureExt, AsyncConnection,
+    AsyncPgConnection, RunQueryDsl,
+};
+use std::collections::HashMap;
+use tracing::{error, info};
+
+// Images handled per transaction.
+const BATCH: i64 = 1000;
+
+// Image id, `tagged_as` and `identified_as` of a segment in it, and when it was captured.
+type Frame = (i32, Option<i32>, Option<i32>, DateTime<Utc>);
+
+pub async fn sessioning_loop(
+    pg_pool: Pool<AsyncPgConnection>,
+    session_gap: u64,
+    session_interval: u64,
+) {
+    loop {
+        if let Err(e) = update_sessions(&pg_pool, Duration::seconds(session_gap as i64)).await {
+            error!("Updating feeding sessions failed: {e:?}");
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(session_interval)).await;
+    }
+}
+
+// Each batch under a lock, as every store process runs this. A process finding it taken leaves
+// the rest of the pass to the one holding it.
+async fn update_sessions(pg_pool: &Pool<AsyncPgConnection>, gap: Duration) -> Result<()> {
+    let mut pg_conn = pg_pool.get().await?;
+    loop {
+        let count = pg_conn
+            .transaction(|pg_conn| {
+                (async move {
+                    if !try_xact_lock(pg_conn, SESSIONING).await? {
+                        return Ok(None);
+                    }
+                    let new: Vec<(i32, Option<i32>, DateTime<Utc>)> = images::table
+                        .filter(images::segmented.eq(true))
+                        .filter(images::sessioned.eq(false))
+                        .select((images::id, images::camera_id, images::captured_at))
+                        .order(images::id)
+                        .limit(BATCH)
+                        .load(pg_conn)
+                        .await?;
+                    let mut windows: HashMap<i32, (DateTime<Utc>, DateTime<Utc>)> = HashMap::new();
+                    for (_, camera_id, captured_at) in &new {
+                        if let Some(camera_id) = camera_id {
+                            let window = windows
+                                .entry(*camera_id)
+                                .or_insert((*captured_at, *captured_at));
+                            window.0 = window.0.min(*captured_at);
+                            window.1 = window.1.max(*captured_at);
+                        }
+                    }
+                    for (camera_id, (from, to)) in windows {
+                        regroup(pg_conn, camera_id, from - gap, to + gap, gap).await?;
+                    }
+                    let ids: Vec<i32> = new.iter().map(|x| x.0).collect();
+                    diesel::update(images::table.filter(images::id.eq_any(&ids)))
+                        .set(images::sessioned.eq(true))
+                        .execute(pg_conn)
+                        .await?;
+                    Ok(Some(ids.len())) as Result<Option<usize>, diesel::result::Error>
+                })
+                .scope_boxed()
+            })
+            .await?;
+        match count {
+            None => {
+                info!("Feeding sessions are being updated by another process");
+                return Ok(());
+            }
+            Some(0) => return Ok(()),
+            Some(count) => info!("Updated feeding sessions around {count} images"),
+        }
+    }
+}
+
+// For images about to go or gone. Sessions they were frames of are dropped, and the images
+// left in them marked for `update_sessions` to regroup, in the transaction of the deletion.
+pub async fn unsession(pg_conn: &mut AsyncPgConnection, images: &[Image]) -> QueryResult<()> {
+    for image in images {
+        let Some(camera_id) = image.camera_id else {
+            continue;
+        };
+        let sessions: Vec<(DateTime<Utc>, DateTime<Utc>)> = diesel::delete(
+            feeding_sessions::table
+                .filter(feeding_sessions::camera_id.eq(camera_id))
+                .filter(feeding_sessions::started_at.le(image.captured_at))
+                .filter(feeding_sessions::ended_at.ge(image.captured_at)),
+        )
+        .returning((feeding_sessions::started_at, feeding_sessions::ended_at))
+        .get_results(pg_conn)
+        .await?;
+        for (started_at, ended_at) in sessions {
+            diesel::update(
+                images::table
+                    .filter(images::camera_id.eq(camera_id))
+                    .filter(images::captured_at.between(started_at, ended_at)),
+            )
+            .set(images::sessioned.eq(false))
+            .execute(pg_conn)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+// Redoes the sessions of all cats at the camera between `from` and `to`.
+// Re-tagging moves frames between cats, so they are all redone together.
+async fn regroup(
+    pg_conn: &mut AsyncPgConnection,
+    camera_id: i32,
+    mut from: DateTime<Utc>,
+    mut to: DateTime<Utc>,
+    gap: Duration,
+) -> QueryResult<()> {
+    // Sessions reaching into the window are redone whole, which may widen it further.
+    loop {
+        let (started_at, ended_at): (Option<DateTime<Utc>>, Option<DateTime<Utc>>) =
+            feeding_sessions::table
+                .filter(feeding_sessions::camera_id.eq(camera_id))
+                .filter(feeding_sessions::started_at.le(to))
+                .filter(feeding_sessions::ended_at.ge(from))
+                .select((
+                    dsl::min(feeding_sessions::started_at),
+                    dsl::max(feeding_sessions::ended_at),
+                ))
+                .first(pg_conn)
+                .await?;
+        match (started_at, ended_at) {
+            (Some(started_at), Some(ended_at)) if started_at < from || ended_at > to => {
+                from = from.min(started_at);
+                to = to.max(ended_at);
+            }
+            _ => break,
+        }
+    }
+    diesel::delete(
+        feeding_sessions::table
+            .filter(feeding_sessions::camera_id.eq(camera_id))
+            .filter(feeding_sessions::started_at.le(to))
+            .filter(feeding_sessions::ended_at.ge(from)),
+    )
+    .execute(pg_conn)
+    .await?;
+
+    let frames: Vec<Frame> = segments::table
+        .inner_join(images::table)
+        .filter(images::camera_id.eq(camera_id))
+        .filter(images::captured_at.between(from, to))
+        .select((
+            images::id,
+            segments::tagged_as,
+            segments::identified_as,
+            images::captured_at,
+        ))
+        .order((images::captured_at, images::id))
+        .load(pg_conn)
+        .await?;
+    let mut open: HashMap<i32, (NewFeedingSession, i32)> = HashMap::new();
+    let mut sessions = Vec::new();
+    for (image_id, tagged_as, identified_as, captured_at) in frames {
+        // A human tag wins over what the identifier thinks.
+        let Some(tag_id) = tagged_as.or(identified_as) else {
+            continue;
+        };
+        match open.get_mut(&tag_id) {
+            Some((session, last_image_id)) if captured_at - session.ended_at <= gap => {
+                // The same cat twice in a frame is still one frame.
+                if *last_image_id != image_id {
+                    session.frame_count += 1;
+                    *last_image_id = image_id;
+                }
+                session.ended_at = captured_at;
+            }
+            _ => {
+                let session = NewFeedingSession {
+                    camera_id,
+                    tag_id,
+                    started_at: captured_at,
+                    ended_at: captured_at,
+                    frame_count: 1,
+                };
+                if let Some((closed, _)) = open.insert(tag_id, (session, image_id)) {
+                    sessions.push(closed);
+                }
+            }
+        }
+    }
+    sessions.extend(open.into_values().map(|(session, _)| session));
+    diesel::insert_into(feeding_sessions::table)
+        .values(&sessions)
+        .execute(pg_conn)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,132 @@
+// Where images and everything made from them are kept, so workers need not share a disk.
+// Names are `/` separated and relative to the root of the storage.
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use object_store::{
+    aws::AmazonS3Builder, local::LocalFileSystem, path::Path, ObjectStore, ObjectStoreExt,
+    PutPayload,
+};
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct Storage {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredObject {
+    pub name: String,
+    pub modified: DateTime<Utc>,
+}
+
+impl Storage {
+    // A folder, or `s3://bucket/prefix`. Credentials, region and the endpoint of S3-compatible
+    // services like MinIO come from the usual `AWS_*` variables, e.g. `AWS_ENDPOINT`.
+    pub fn open(location: &str) -> Result<Storage> {
+        if let Some(bucket) = location.strip_prefix("s3://") {
+            let (bucket, prefix) = bucket.split_once('/').unwrap_or((bucket, ""));
+            if bucket.is_empty() {
+                Err(anyhow!("No bucket in {location}"))?;
+            }
+            let store = AmazonS3Builder::from_env()
+                .with_bucket_name(bucket)
+                .build()?;
+            Ok(Storage {
+                store: Arc::new(store),
+                prefix: Path::parse(prefix)?,
+            })
+        } else {
+            std::fs::create_dir_all(location)?;
+            Ok(Storage {
+                store: Arc::new(LocalFileSystem::new_with_prefix(location)?),
+                prefix: Path::default(),
+            })
+        }
+    }
+
+    fn path(&self, name: &str) -> Path {
+        self.prefix
+            .parts()
+            .chain(Path::from(name).parts())
+            .collect()
+    }
+
+    fn name(&self, path: &Path) -> Option<String> {
+        let parts: Vec<_> = path.prefix_match(&self.prefix)?.collect();
+        Some(
+            parts
+                .iter()
+                .map(|x| x.as_ref())
+                .collect::<Vec<_>>()
+                .join("/"),
+        )
+    }
+
+    pub async fn get(&self, name: &str) -> Result<Vec<u8>> {
+        let content = self.store.get(&self.path(name)).await?.bytes().await?;
+        Ok(content.to_vec())
+    }
+
+    pub async fn try_get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.get(name).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn put(&self, name: &str, content: Vec<u8>) -> Result<()> {
+        self.store
+            .put(&self.path(name), PutPayload::from(content))
+            .await?;
+        Ok(())
+    }
+
+    // Gone already is fine.
+    pub async fn delete(&self, name: &str) -> Result<()> {
+        match self.store.delete(&self.path(name)).await {
+            Err(object_store::Error::NotFound { .. }) | Ok(_) => Ok(()),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    // Objects in `folder` and its subfolders.
+    pub async fn list(&self, folder: Option<&str>) -> Result<Vec<StoredObject>> {
+        let prefix = match folder {
+            Some(folder) => self.path(folder),
+            None => self.prefix.clone(),
+        };
+        let listed: Vec<_> = self.store.list(Some(&prefix)).try_collect().await?;
+        Ok(listed
+            .into_iter()
+            .filter_map(|x| {
+                Some(StoredObject {
+                    name: self.name(&x.location)?,
+                    modified: x.last_modified,
+                })
+            })
+            .collect())
+    }
+
+    // Names of the folders right in `folder`, without listing what is in them.
+    pub async fn folders(&self, folder: &str) -> Result<Vec<String>> {
+        let listed = self
+            .store
+            .list_with_delimiter(Some(&self.path(folder)))
+            .await?;
+        Ok(listed
+            .common_prefixes
+            .iter()
+            .filter_map(|x| self.name(x))
+            .collect())
+    }
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<object_store::Error>(),
+        Some(object_store::Error::NotFound { .. })
+    )
+}
@@ -0,0 +1,101 @@
+// Live events for dashboards. Any process publishes to a Redis channel, and each Store process
+// relays the channel to its Server-Sent Events clients. Events nobody listens to are gone.
+use crate::app_state::StoreState;
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client};
+use redis_pool::RedisPool;
+use rocket::{
+    get,
+    response::stream::{Event, EventStream},
+    tokio::{select, sync::broadcast},
+    Shutdown, State,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::error;
+
+pub const CHANNEL: &str = "jianai-events";
+// An image was recorded, by any kind of upload.
+pub const IMAGE_UPLOADED: &str = "image.uploaded";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LiveEvent {
+    pub event: String,
+    pub data: Value,
+}
+
+// Failing to tell dashboards is not worth failing what happened.
+pub async fn publish(
+    redis_pool: &RedisPool<Client, MultiplexedConnection>,
+    event: &str,
+    data: Value,
+) {
+    let result: Result<()> = async {
+        let message = serde_json::to_string(&LiveEvent {
+            event: event.to_string(),
+            data,
+        })?;
+        let mut redis = redis_pool.aquire().await?;
+        redis.publish::<_, _, i64>(CHANNEL, message).await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        error!("Publishing {event} failed: {e:?}");
+    }
+}
+
+pub async fn relaying_loop(redis_client: Client, events: broadcast::Sender<String>) {
+    loop {
+        if let Err(e) = relay(&redis_client, &events).await {
+            error!("Relaying live events failed: {e:?}");
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    }
+}
+
+async fn relay(redis_client: &Client, events: &broadcast::Sender<String>) -> Result<()> {
+    let mut pubsub = redis_client.get_async_pubsub().await?;
+    pubsub.subscribe(CHANNEL).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        // Having no clients is fine.
+        let _ = events.send(message.get_payload()?);
+    }
+    Err(anyhow!("Subscription to {CHANNEL} ended"))
+}
+
+// All events, or the ones named in `event`, as they happen.
+// Not instrumented, as `instrument` cannot wrap the stream type.
+#[get("/?<event>")]
+pub fn stream_events(
+    state: &State<StoreState>,
+    event: Vec<String>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut receiver = state.events.subscribe();
+    EventStream! {
+        loop {
+            let message = select! {
+                message = receiver.recv() => message,
+                _ = &mut shutdown => break,
+            };
+            match message {
+                Ok(message) => {
+                    let Ok(live) = serde_json::from_str::<LiveEvent>(&message) else {
+                        continue;
+                    };
+                    if event.is_empty() || event.contains(&live.event) {
+                        yield Event::json(&live.data).event(live.event);
+                    }
+                }
+                // A slow client misses events rather than holding up the others.
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    yield Event::comment(format!("{missed} events missed"));
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
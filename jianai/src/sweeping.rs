@@ -0,0 +1,159 @@
+use crate::app_state::{upload_key, PERSISTING_KEY};
+use crate::schema::*;
+use crate::storage::Storage;
+use anyhow::Result;
+use chrono::Utc;
+use diesel::{dsl::exists, ExpressionMethods, QueryDsl};
+use diesel_async::{pooled_connection::bb8::Pool, AsyncPgConnection, RunQueryDsl};
+use redis::{aio::MultiplexedConnection, AsyncCommands, Client};
+use redis_pool::RedisPool;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info};
+
+pub async fn sweeping_loop(
+    redis_pool: RedisPool<Client, MultiplexedConnection>,
+    pg_pool: Pool<AsyncPgConnection>,
+    storage: Storage,
+    partial_folder: PathBuf,
+    upload_session_ttl: u64,
+    sweep_interval: u64,
+) {
+    if let Err(e) = expire_legacy_sessions(&redis_pool, upload_session_ttl).await {
+        error!("Expiring upload sessions from before the upgrade failed: {e:?}");
+    }
+    loop {
+        if let Err(e) = sweep(
+            &redis_pool,
+            &pg_pool,
+            &storage,
+            &partial_folder,
+            upload_session_ttl,
+        )
+        .await
+        {
+            error!("Sweeping failed: {e:?}");
+        }
+        tokio::time::sleep(Duration::from_secs(sweep_interval)).await;
+    }
+}
+
+// Keys of this program that are not upload sessions.
+const OTHER_KEYS: [&str; 2] = ["segmenting-", "appending-"];
+
+// Before upload sessions expired, they were kept under the bare upload id, holding only the
+// metadata. Those can not be finished, as they name no camera, so clients get 410 and start
+// over. Their keys are given the session TTL once per start, which takes a scan of all keys.
+async fn expire_legacy_sessions(
+    redis_pool: &RedisPool<Client, MultiplexedConnection>,
+    upload_session_ttl: u64,
+) -> Result<()> {
+    let mut redis = redis_pool.aquire().await?;
+    let mut keys: Vec<String> = Vec::new();
+    let mut iter = redis.scan::<String>().await?;
+    while let Some(key) = iter.next_item().await {
+        keys.push(key);
+    }
+    drop(iter);
+    for key in keys {
+        if key.starts_with(&upload_key("")) || OTHER_KEYS.iter().any(|x| key.starts_with(x)) {
+            continue;
+        }
+        let ttl: i64 = redis.ttl(&key).await?;
+        // Not a string, so not ours.
+        let value: Option<String> = redis.get(&key).await.unwrap_or(None);
+        if ttl == -1 && value.as_deref().is_some_and(is_metadata) {
+            info!("Expiring upload session {key} from before the upgrade");
+            redis.expire(&key, upload_session_ttl.try_into()?).await?;
+        }
+    }
+    Ok(())
+}
+
+// What `upload_meta` stored before sessions were `PendingUpload`s.
+fn is_metadata(value: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(value).is_ok_and(|x| x.is_object())
+}
+
+async fn sweep(
+    redis_pool: &RedisPool<Client, MultiplexedConnection>,
+    pg_pool: &Pool<AsyncPgConnection>,
+    storage: &Storage,
+    partial_folder: &Path,
+    upload_session_ttl: u64,
+) -> Result<()> {
+    info!("Sweeping abandoned uploads");
+    let mut redis = redis_pool.aquire().await?;
+
+    // Images put in storage by uploads whose row never made it to the DB, as noted in
+    // `PERSISTING_KEY`. Recent ones may still be on their way. Orphans from before files were
+    // noted are left to `fsck`.
+    let deadline = SystemTime::now() - Duration::from_secs(upload_session_ttl);
+    let stored_deadline = (Utc::now() - Duration::from_secs(upload_session_ttl)).timestamp();
+    let candidates: Vec<String> = redis
+        .zrangebyscore(PERSISTING_KEY, "-inf", stored_deadline)
+        .await?;
+    let mut pg_conn = pg_pool.get().await?;
+    let known: HashSet<String> = images::table
+        .filter(images::filename.eq_any(&candidates))
+        .select(images::filename)
+        .load::<String>(&mut pg_conn)
+        .await?
+        .into_iter()
+        .collect();
+    for name in candidates.into_iter().filter(|name| !known.contains(name)) {
+        // The same content has the same name, so an upload of it again may be on its way now.
+        // It notes the file anew before putting it, and unnotes it once its row is in.
+        let noted: Option<f64> = redis.zscore(PERSISTING_KEY, &name).await?;
+        if !noted.is_some_and(|x| x <= stored_deadline as f64) {
+            continue;
+        }
+        let has_row: bool =
+            diesel::select(exists(images::table.filter(images::filename.eq(&name))))
+                .get_result(&mut pg_conn)
+                .await?;
+        if has_row {
+            continue;
+        }
+        info!("Removing orphaned {name}");
+        storage.delete(&name).await?;
+    }
+    // Noted again since is newer, and stays.
+    redis
+        .zrembyscore(PERSISTING_KEY, "-inf", stored_deadline)
+        .await?;
+
+    // Resumable uploads whose session is gone.
+    if tokio::fs::try_exists(partial_folder).await? {
+        let mut dir = tokio::fs::read_dir(partial_folder).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() && metadata.modified()? < deadline {
+                if let Some(name) = entry.file_name().to_str() {
+                    if !redis.exists::<_, bool>(upload_key(name)).await? {
+                        info!("Removing partial {name}");
+                        tokio::fs::remove_file(entry.path()).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_sessions_hold_metadata() {
+        assert!(is_metadata(r#"{"captured_at": "2024-04-01T08:00:00"}"#));
+        assert!(!is_metadata("worker-1"));
+        assert!(!is_metadata("[1, 2]"));
+        assert!(!is_metadata("42"));
+    }
+}
@@ -5,11 +5,12 @@ use diesel::{
     AppearsOnTable,
     query_builder::{ QueryId, QueryFragment },
 };
-use serde::Serialize;
+use serde::{ Serialize, Deserialize, Deserializer };
+use chrono::{ DateTime, NaiveDate, Utc };
 use crate::schema::sql_types;
 use byteorder::{ NetworkEndian, ReadBytesExt };
 
-#[derive(Debug, Clone, Copy, QueryId, Serialize)]
+#[derive(Debug, Clone, Copy, QueryId, Serialize, Deserialize)]
 pub struct Box {
     pub point1: Point,
     pub point2: Point,
@@ -37,7 +38,8 @@ impl FromSql<sql_types::Box, Pg> for Box {
         // } else {
         //     Err(anyhow::anyhow!("Unable to parse Postgresql returned value as Box").into())
         // }
-        Ok(Box { point1: Point { x: x1, y: y1 }, point2: Point { x: x2, y: y2 } })
+        // Postgres stores the upper right corner first, whichever order was inserted.
+        Ok(Box { point1: Point { x: x1, y: y1 }, point2: Point { x: x2, y: y2 } }.normalized())
     }
 }
 // impl ToSql<sql_types::Box, Pg> for Box {
@@ -86,14 +88,21 @@ impl Box {
     // The first two functions are copied from example. I guess `geo` can be introduced.
 
     pub fn intersection_area(&self, another: &Box) -> f32 {
-        (self.point2.x.min(another.point2.x) - self.point1.x.max(another.point1.x)) *
-            (self.point2.y.min(another.point2.y) - self.point1.y.max(another.point1.y))
+        // Boxes apart on both axes would otherwise multiply two negatives.
+        (self.point2.x.min(another.point2.x) - self.point1.x.max(another.point1.x)).max(0.0) *
+            (self.point2.y.min(another.point2.y) - self.point1.y.max(another.point1.y)).max(0.0)
     }
 
     pub fn union_area(self, another: &Box) -> f32 {
         self.area() + another.area() - self.intersection_area(another)
     }
 
+    // A box of no area overlaps nothing.
+    pub fn iou(self, another: &Box) -> f32 {
+        let union = self.union_area(another);
+        if union <= 0.0 { 0.0 } else { self.intersection_area(another) / union }
+    }
+
     pub fn height(self) -> f32 {
         self.point2.y - self.point1.y
     }
@@ -105,10 +114,248 @@ impl Box {
     pub fn area(self) -> f32 {
         self.height() * self.width()
     }
+
+    // `point1` the lower corner and `point2` the upper one, as the rest of this impl expects.
+    pub fn normalized(self) -> Box {
+        Box {
+            point1: Point {
+                x: self.point1.x.min(self.point2.x),
+                y: self.point1.y.min(self.point2.y),
+            },
+            point2: Point {
+                x: self.point1.x.max(self.point2.x),
+                y: self.point1.y.max(self.point2.y),
+            },
+        }
+    }
+
+    pub fn center(self) -> Point {
+        Point {
+            x: (self.point1.x + self.point2.x) / 2.0,
+            y: (self.point1.y + self.point2.y) / 2.0,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Point {
     pub x: f32,
     pub y: f32,
 }
+
+// What `upload_meta` leaves in Redis for `upload_image` to pick up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingUpload {
+    pub camera_id: i32,
+    pub captured_at: DateTime<Utc>,
+    pub metadata: serde_json::Value,
+    // Set once a resumable upload is created.
+    #[serde(default)]
+    pub upload_length: Option<u64>,
+    #[serde(default)]
+    pub expected_digest: Option<Vec<u8>>,
+}
+
+// Feeding sessions of a cat in a day or week, starting on `start`, local time.
+// Trailing ones are averages over the periods before, with periods without visits counting.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeriodStats {
+    pub start: NaiveDate,
+    pub visits: i64,
+    pub total_seconds: i64,
+    // When the first visit of the period started and the last one ended.
+    pub first_visit: Option<DateTime<Utc>>,
+    pub last_visit: Option<DateTime<Utc>>,
+    pub trailing_visits: f64,
+    pub trailing_seconds: f64,
+    // Against the trailing averages, 1 being as usual. Missing without a history.
+    pub visits_vs_trailing: Option<f64>,
+    pub seconds_vs_trailing: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CatStats {
+    pub tag_id: i32,
+    pub tag: String,
+    pub periods: Vec<PeriodStats>,
+}
+
+// Answers of a reviewer between two times.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReviewerStats {
+    pub reviewer: String,
+    pub answered: i64,
+    pub tagged: i64,
+    pub low_quality: i64,
+    // From leasing to answering.
+    pub average_seconds: f64,
+    pub first_answered_at: Option<DateTime<Utc>>,
+    pub last_answered_at: Option<DateTime<Utc>>,
+}
+
+// How the identifier did against human tags. `tagged` counts all the human tagged so, `covered`
+// those of them it identified as any cat. Precision is over what it called the tag, recall over
+// what it covered, so a cat nothing identifies yet has none.
+#[derive(Debug, Clone, Serialize)]
+pub struct TagAccuracy {
+    pub tag_id: i32,
+    pub tag: String,
+    pub tagged: i64,
+    pub covered: i64,
+    pub identified: i64,
+    pub correct: i64,
+    pub precision: Option<f64>,
+    pub recall: Option<f64>,
+}
+
+// Segments tagged `tagged_as` that the identifier called `identified_as`, none if it did not.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfusionCell {
+    pub tagged_as: String,
+    pub identified_as: Option<String>,
+    pub count: i64,
+}
+
+// For a period starting on `start` (UTC), or for a model version, missing if not recorded.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupAccuracy {
+    pub start: Option<NaiveDate>,
+    pub model: Option<String>,
+    pub total: i64,
+    pub identified: i64,
+    pub correct: i64,
+    pub accuracy: Option<f64>,
+}
+
+// Coverage is how many of the tagged segments were identified at all, accuracy how many of
+// those identified were right. Unidentified segments only count towards coverage.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccuracyReport {
+    pub total: i64,
+    pub identified: i64,
+    pub coverage: Option<f64>,
+    pub correct: i64,
+    pub accuracy: Option<f64>,
+    pub tags: Vec<TagAccuracy>,
+    pub confusion: Vec<ConfusionCell>,
+    pub periods: Vec<GroupAccuracy>,
+    pub models: Vec<GroupAccuracy>,
+}
+
+// Human-verified boxes of the cats in an image. Corners may come in either order.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroundTruth {
+    pub image_id: i32,
+    #[serde(deserialize_with = "normalized_boxes")]
+    pub boxes: Vec<Box>,
+}
+
+fn normalized_boxes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Box>, D::Error> {
+    Ok(Vec::<Box>::deserialize(deserializer)?.into_iter().map(Box::normalized).collect())
+}
+
+// What the detector got wrong in an image, at the threshold and IoU 0.5.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImageEvaluation {
+    pub image_id: i32,
+    pub ground_truth: usize,
+    pub detected: usize,
+    pub missed: Vec<Box>,
+    pub false_positives: Vec<Box>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectionReport {
+    pub model: String,
+    pub threshold: f32,
+    pub images: usize,
+    pub ground_truth: usize,
+    // Over every detection, however unsure.
+    pub map50: f64,
+    pub map50_95: f64,
+    // Of detections at the threshold, at IoU 0.5.
+    pub precision: Option<f64>,
+    pub recall: Option<f64>,
+    pub per_image: Vec<ImageEvaluation>,
+}
+
+// When an alert rule fires, kept as JSON in `alert_rules.condition`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Condition {
+    // No feeding session of the cat ended in the last `hours`.
+    NotSeen { tag_id: i32, hours: u32 },
+    // More than `visits` feeding sessions of the cat since midnight in `timezone`, UTC by default.
+    TooManyVisits { tag_id: i32, visits: u32, timezone: Option<String> },
+    // A cat neither identified nor tagged in the last `hours`, at the camera or any. Only
+    // detections sure enough to be cats count.
+    UnknownCat { camera_id: Option<i32>, hours: u32 },
+}
+
+// What `retaining` removes, from a JSON file. Ages are in days since captured.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetentionRules {
+    // Only the original goes, what is known about the image stays. Once segmented, and this long
+    // after upload too.
+    pub originals_days: Option<u32>,
+    // Images segmented without finding a cat go altogether.
+    pub catless_days: Option<u32>,
+    // Crops of segments tagged by a human outlive their originals.
+    #[serde(default)]
+    pub keep_tagged_crops: bool,
+    // Pack originals into tar files before removing them.
+    #[serde(default)]
+    pub archive: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MetaError {
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum UploadStatus {
+    Pending { expires_in: Option<i64> },
+    Completed { image_id: i32 },
+    // Also what unknown ids look like, as nothing is kept of expired sessions.
+    Expired,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: f32, y: f32, side: f32) -> Box {
+        Box { point1: Point { x, y }, point2: Point { x: x + side, y: y + side } }
+    }
+
+    #[test]
+    fn iou_of_overlapping_boxes() {
+        let a = square(0.0, 0.0, 2.0);
+        assert_eq!(a.iou(&a), 1.0);
+        // 2 of 4 + 4 - 2.
+        assert!((a.iou(&square(1.0, 0.0, 2.0)) - 1.0 / 3.0).abs() < 1e-6);
+        // 1 of 4 + 4 - 1.
+        assert!((a.iou(&square(1.0, 1.0, 2.0)) - 1.0 / 7.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn iou_of_apart_and_empty_boxes() {
+        let a = square(0.0, 0.0, 2.0);
+        assert_eq!(a.iou(&square(5.0, 5.0, 2.0)), 0.0);
+        assert_eq!(a.iou(&square(5.0, 0.0, 2.0)), 0.0);
+        let dot = square(1.0, 1.0, 0.0);
+        assert_eq!(dot.iou(&dot), 0.0);
+    }
+
+    #[test]
+    fn ground_truth_boxes_are_normalized() {
+        let truth: GroundTruth = serde_json::from_str(
+            r#"{"image_id": 1, "boxes": [{"point1": {"x": 2, "y": 3}, "point2": {"x": 0, "y": 1}}]}"#
+        ).unwrap();
+        let b = truth.boxes[0];
+        assert_eq!((b.point1.x, b.point1.y, b.point2.x, b.point2.y), (0.0, 1.0, 2.0, 3.0));
+    }
+}
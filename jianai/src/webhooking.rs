@@ -0,0 +1,200 @@
+// Tells home automation about what happened. Events are queued in `webhook_deliveries` in the
+// same transaction as what they are about, then posted with retries, so none are lost.
+use crate::ingest::hex;
+use crate::model::{NewWebhookDelivery, Webhook, WebhookDelivery};
+use crate::schema::*;
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use diesel::{
+    ExpressionMethods, PgArrayExpressionMethods, QueryDsl, QueryResult, SelectableHelper,
+};
+use diesel_async::{
+    pooled_connection::bb8::Pool, scoped_futures::ScopedFutureExt, AsyncConnection,
+    AsyncPgConnection, RunQueryDsl,
+};
+use hmac::{Hmac, KeyInit, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tracing::{error, info, warn};
+
+// An image was segmented, with its `SegmentWithTag`s.
+pub const IMAGE_SEGMENTED: &str = "image.segmented";
+// A segment was tagged as another cat, with the `SegmentWithTag` and its previous tag id.
+pub const CAT_IDENTIFIED: &str = "cat.identified";
+// An alert rule fired, with the `Alert`.
+pub const ALERT_FIRED: &str = "alert.fired";
+// Sent on request only, to try a receiver.
+pub const PING: &str = "ping";
+pub const EVENTS: [&str; 3] = [IMAGE_SEGMENTED, CAT_IDENTIFIED, ALERT_FIRED];
+
+// Deliveries claimed at a time, then sent one after another.
+const BATCH: i64 = 10;
+// How long one attempt may take.
+const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+// Claimed deliveries are left alone for twice as long as a whole batch may take, so another
+// instance does not claim them again while they are still being sent.
+const LEASE_SECONDS: i64 = 2 * BATCH * TIMEOUT.as_secs() as i64;
+// Waits double from this after every failed attempt, about 4 hours in all.
+const BACKOFF_SECONDS: i64 = 30;
+const MAX_ATTEMPTS: i32 = 10;
+
+// Queues the event for every enabled webhook that wants it.
+pub async fn enqueue(
+    pg_conn: &mut AsyncPgConnection,
+    event: &str,
+    payload: Value,
+) -> QueryResult<()> {
+    let webhook_ids: Vec<i32> = webhooks::table
+        .filter(webhooks::enabled.eq(true))
+        .filter(webhooks::events.contains(vec![event]))
+        .select(webhooks::id)
+        .load(pg_conn)
+        .await?;
+    let deliveries: Vec<_> = webhook_ids
+        .into_iter()
+        .map(|webhook_id| NewWebhookDelivery {
+            webhook_id,
+            event: event.to_string(),
+            payload: payload.clone(),
+        })
+        .collect();
+    diesel::insert_into(webhook_deliveries::table)
+        .values(&deliveries)
+        .execute(pg_conn)
+        .await?;
+    Ok(())
+}
+
+pub async fn webhooking_loop(pg_pool: Pool<AsyncPgConnection>, webhook_interval: u64) {
+    let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Preparing webhooks failed: {e:?}");
+            return;
+        }
+    };
+    loop {
+        if let Err(e) = deliver(&pg_pool, &client).await {
+            error!("Delivering webhooks failed: {e:?}");
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(webhook_interval)).await;
+    }
+}
+
+async fn deliver(pg_pool: &Pool<AsyncPgConnection>, client: &reqwest::Client) -> Result<()> {
+    let mut pg_conn = pg_pool.get().await?;
+    loop {
+        // Claimed for a while, so other instances do not send them too.
+        let due: Vec<(WebhookDelivery, Webhook)> = pg_conn
+            .transaction(|pg_conn| {
+                (async move {
+                    let now = Utc::now();
+                    let due: Vec<(WebhookDelivery, Webhook)> = webhook_deliveries::table
+                        .inner_join(webhooks::table)
+                        .filter(webhook_deliveries::delivered_at.is_null())
+                        .filter(webhook_deliveries::failed_at.is_null())
+                        .filter(webhook_deliveries::next_attempt_at.le(now))
+                        .filter(webhooks::enabled.eq(true))
+                        .select((WebhookDelivery::as_select(), Webhook::as_select()))
+                        .order(webhook_deliveries::id)
+                        .limit(BATCH)
+                        .for_update()
+                        .skip_locked()
+                        .load(pg_conn)
+                        .await?;
+                    let ids: Vec<i32> = due.iter().map(|x| x.0.id).collect();
+                    diesel::update(
+                        webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)),
+                    )
+                    .set(
+                        webhook_deliveries::next_attempt_at
+                            .eq(now + Duration::seconds(LEASE_SECONDS)),
+                    )
+                    .execute(pg_conn)
+                    .await?;
+                    Ok(due) as Result<_, diesel::result::Error>
+                })
+                .scope_boxed()
+            })
+            .await?;
+        if due.is_empty() {
+            return Ok(());
+        }
+        for (delivery, webhook) in due {
+            let (status_code, last_error): (Option<i32>, Option<String>) =
+                match send(client, &webhook, &delivery).await {
+                    Ok(status) if status.is_success() => (Some(status.as_u16().into()), None),
+                    Ok(status) => (Some(status.as_u16().into()), Some(status.to_string())),
+                    Err(e) => (None, Some(format!("{e:?}"))),
+                };
+            let attempts = delivery.attempts + 1;
+            let now = Utc::now();
+            let query = diesel::update(webhook_deliveries::table.find(delivery.id));
+            let fields = (
+                webhook_deliveries::attempts.eq(attempts),
+                webhook_deliveries::status_code.eq(status_code),
+                webhook_deliveries::last_error.eq(&last_error),
+            );
+            match &last_error {
+                None => {
+                    query
+                        .set((fields, webhook_deliveries::delivered_at.eq(now)))
+                        .execute(&mut pg_conn)
+                        .await?;
+                }
+                Some(e) if attempts >= MAX_ATTEMPTS => {
+                    warn!(
+                        "Gave up delivering {} to {} after {attempts} attempts: {e}",
+                        delivery.id, webhook.url
+                    );
+                    query
+                        .set((fields, webhook_deliveries::failed_at.eq(now)))
+                        .execute(&mut pg_conn)
+                        .await?;
+                }
+                Some(e) => {
+                    info!(
+                        "Delivering {} to {} failed, will retry: {e}",
+                        delivery.id, webhook.url
+                    );
+                    let backoff = Duration::seconds(BACKOFF_SECONDS << (attempts - 1));
+                    query
+                        .set((
+                            fields,
+                            webhook_deliveries::next_attempt_at.eq(now + backoff),
+                        ))
+                        .execute(&mut pg_conn)
+                        .await?;
+                }
+            }
+        }
+    }
+}
+
+// The receiver checks `X-Jianai-Signature` against the HMAC-SHA256 of the body with the secret,
+// and may use `id` to ignore deliveries it has seen, as a lost response means sending again.
+async fn send(
+    client: &reqwest::Client,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+) -> Result<reqwest::StatusCode> {
+    let body = serde_json::to_vec(&json!({
+        "id": delivery.id,
+        "event": delivery.event,
+        "created_at": delivery.created_at,
+        "data": delivery.payload,
+    }))?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(webhook.secret.as_bytes())?;
+    mac.update(&body);
+    let signature = hex(&mac.finalize().into_bytes());
+    let response = client
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Jianai-Event", &delivery.event)
+        .header("X-Jianai-Delivery", delivery.id)
+        .header("X-Jianai-Signature", format!("sha256={signature}"))
+        .body(body)
+        .send()
+        .await?;
+    Ok(response.status())
+}
@@ -0,0 +1,174 @@
+// Managing webhooks, and looking at their delivery log.
+use crate::app_state::StoreState;
+use crate::handlers::*;
+use crate::model::{NewWebhook, Webhook, WebhookDelivery};
+use crate::schema::*;
+use crate::webhooking::{EVENTS, PING};
+use chrono::Utc;
+use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+use diesel_async::RunQueryDsl;
+use rocket::{http::Status, serde::json::Json, *};
+use serde_json::json;
+use tracing::instrument;
+
+fn check_webhook(webhook: &NewWebhook) -> anyhow::Result<()> {
+    if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+        Err(BadRequest(format!("Not an HTTP URL: {}", webhook.url)))?;
+    }
+    if let Some(event) = webhook
+        .events
+        .iter()
+        .find(|x| !EVENTS.contains(&x.as_str()))
+    {
+        Err(BadRequest(format!(
+            "Unknown event {event}, expected one of {}",
+            EVENTS.join(", ")
+        )))?;
+    }
+    Ok(())
+}
+
+#[instrument]
+#[get("/")]
+pub async fn list_webhooks(
+    state: &State<StoreState>,
+) -> Result<Json<Vec<Webhook>>, (Status, String)> {
+    let result: anyhow::Result<Vec<Webhook>> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        webhooks::table
+            .select(Webhook::as_select())
+            .order(webhooks::id)
+            .load(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+#[instrument(skip(webhook))]
+#[post("/", format = "application/json", data = "<webhook>")]
+pub async fn create_webhook(
+    state: &State<StoreState>,
+    webhook: Json<NewWebhook>,
+) -> Result<Json<Webhook>, (Status, String)> {
+    let result: anyhow::Result<Webhook> = try {
+        check_webhook(&webhook)?;
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::insert_into(webhooks::table)
+            .values(webhook.into_inner())
+            .returning(Webhook::as_returning())
+            .get_result(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+// Queued deliveries go to the new URL, signed with the new secret.
+#[instrument(skip(webhook))]
+#[put("/<id>", format = "application/json", data = "<webhook>")]
+pub async fn update_webhook(
+    state: &State<StoreState>,
+    id: i32,
+    webhook: Json<NewWebhook>,
+) -> Result<Json<Webhook>, (Status, String)> {
+    let result: anyhow::Result<Webhook> = try {
+        check_webhook(&webhook)?;
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::update(webhooks::table.find(id))
+            .set(webhook.into_inner())
+            .returning(Webhook::as_returning())
+            .get_result(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+// Its deliveries go with it.
+#[instrument]
+#[delete("/<id>")]
+pub async fn delete_webhook(state: &State<StoreState>, id: i32) -> (Status, String) {
+    let result: anyhow::Result<usize> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::delete(webhooks::table.find(id))
+            .execute(&mut pg_conn)
+            .await?
+    };
+    match result {
+        Ok(0) => (Status::NotFound, String::new()),
+        Ok(_) => (Status::Ok, String::new()),
+        Err(e) => error_response(e),
+    }
+}
+
+// Queues a `ping` to the webhook, whatever its events, to try the receiver.
+#[instrument]
+#[post("/<id>/ping")]
+pub async fn ping_webhook(state: &State<StoreState>, id: i32) -> (Status, String) {
+    let result: anyhow::Result<()> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        let webhook: Webhook = webhooks::table
+            .find(id)
+            .select(Webhook::as_select())
+            .first(&mut pg_conn)
+            .await?;
+        diesel::insert_into(webhook_deliveries::table)
+            .values((
+                webhook_deliveries::webhook_id.eq(webhook.id),
+                webhook_deliveries::event.eq(PING),
+                webhook_deliveries::payload.eq(json!({ "webhook_id": webhook.id })),
+            ))
+            .execute(&mut pg_conn)
+            .await?;
+    };
+    match result {
+        Ok(()) => (Status::Accepted, String::new()),
+        Err(e) => error_response(e),
+    }
+}
+
+// Latest first. With `failed`, only the ones given up on.
+#[instrument]
+#[get("/<id>/deliveries?<failed>&<limit>")]
+pub async fn list_deliveries(
+    state: &State<StoreState>,
+    id: i32,
+    failed: Option<bool>,
+    limit: Option<i64>,
+) -> Result<Json<Vec<WebhookDelivery>>, (Status, String)> {
+    let result: anyhow::Result<Vec<WebhookDelivery>> = try {
+        let mut query = webhook_deliveries::table
+            .filter(webhook_deliveries::webhook_id.eq(id))
+            .select(WebhookDelivery::as_select())
+            .order(webhook_deliveries::id.desc())
+            .limit(limit.unwrap_or(100))
+            .into_boxed();
+        if failed.unwrap_or(false) {
+            query = query.filter(webhook_deliveries::failed_at.is_not_null());
+        }
+        let mut pg_conn = state.pg_pool.get().await?;
+        query.load(&mut pg_conn).await?
+    };
+    result.map(Json).map_err(error_response)
+}
+
+// Sends a delivery again from the start, say after fixing the receiver.
+#[instrument]
+#[post("/deliveries/<id>/retry")]
+pub async fn retry_delivery(
+    state: &State<StoreState>,
+    id: i32,
+) -> Result<Json<WebhookDelivery>, (Status, String)> {
+    let result: anyhow::Result<WebhookDelivery> = try {
+        let mut pg_conn = state.pg_pool.get().await?;
+        diesel::update(webhook_deliveries::table.find(id))
+            .set((
+                webhook_deliveries::attempts.eq(0),
+                webhook_deliveries::next_attempt_at.eq(Utc::now()),
+                webhook_deliveries::delivered_at.eq(None::<chrono::DateTime<Utc>>),
+                webhook_deliveries::failed_at.eq(None::<chrono::DateTime<Utc>>),
+            ))
+            .returning(WebhookDelivery::as_returning())
+            .get_result(&mut pg_conn)
+            .await?
+    };
+    result.map(Json).map_err(error_response)
+}